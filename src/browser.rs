@@ -0,0 +1,273 @@
+extern crate ncurses;
+use ncurses::*;
+use std::char;
+use std::cmp::{min, max};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::colors;
+use crate::prompt::{char_width, str_width};
+
+// Filename completion, and a file browser for picking a path, for the
+// filename prompts. Both are drawn in a window on top of the editor, which
+// is put back when they're closed
+
+pub fn complete_path(partial: &str) -> (String, Vec<String>) {
+    // Completes the last component of partial against the filesystem
+    // Returns the input extended as far as it is unambiguous, and the
+    // candidates if there is more than one (directories end with '/')
+    let split = partial.rfind('/').map_or(0, |i| i + 1);
+    let (dir_part, prefix) = partial.split_at(split);
+
+    let mut candidates: Vec<String> = match fs::read_dir(expand_home(dir_part)) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
+            let mut name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            Some(name)
+        }).collect(),
+        Err(_) => Vec::new()
+    };
+    candidates.sort();
+
+    match candidates.len() {
+        0 => (partial.to_string(), candidates),
+        1 => (format!("{}{}", dir_part, candidates[0]), Vec::new()),
+        _ => {
+            let common = common_prefix(&candidates);
+            (format!("{}{}", dir_part, common), candidates)
+        }
+    }
+}
+
+pub fn show_listing(over: WINDOW, names: &[String]) -> WINDOW {
+    // Lists names in columns in a new window over the given one
+    // The listing stays up until it's closed with close_window
+    let window = overlay_window(over);
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(window, &mut max_y, &mut max_x);
+
+    let col_width = column_width(names, max_x as usize);
+    let cols = max(1, max_x as usize / col_width);
+    let capacity = cols * max(1, max_y as usize);
+    for (i, name) in names.iter().enumerate() {
+        let (row, col) = ((i / cols) as i32, (i % cols * col_width) as i32);
+        if i + 1 == capacity && names.len() > capacity {
+            // No room for the rest
+            mvwaddstr(window, row, col, &fit(&format!("({} more)", names.len() - i), col_width - 1)).unwrap();
+            break;
+        }
+        mvwaddstr(window, row, col, &fit(name, col_width - 1)).unwrap();
+    }
+    wrefresh(window);
+    window
+}
+
+pub fn close_window(window: WINDOW, over: WINDOW) {
+    // Removes a listing or browser window, showing what was under it again
+    delwin(window);
+    touchwin(over);
+    wrefresh(over);
+}
+
+pub fn browse(over: WINDOW, ctrl_window: WINDOW, start: &str) -> Option<String> {
+    // Lets the user walk the directories, starting from the one start is
+    // in, and pick a file. Returns the picked file's path, relative to the
+    // working directory if it's under it
+
+    let start = expand_home(start);
+    let start_dir = if start.is_dir() {
+        start
+    } else {
+        match start.parent() {
+            Some(parent) if parent.is_dir() => parent.to_path_buf(),
+            _ => PathBuf::from(".")
+        }
+    };
+    let mut dir = match fs::canonicalize(start_dir) {
+        Ok(dir) => dir,
+        Err(_) => { return None; }
+    };
+    let mut entries = read_entries(&dir)?;
+
+    let window = overlay_window(over);
+    keypad(window, true);
+    curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+    let mut selected = 0;
+    let mut top_row = 0;
+
+    let mut ret = None;
+    loop {
+        let mut max_x = 0;
+        let mut max_y = 0;
+        getmaxyx(window, &mut max_y, &mut max_x);
+        let names: Vec<String> = entries.iter().map(|(name, is_dir)| if *is_dir { format!("{}/", name) } else { name.clone() }).collect();
+        let col_width = column_width(&names, max_x as usize);
+        let cols = max(1, max_x as usize / col_width);
+        let rows = max(1, max_y as usize);
+
+        // Scroll so that the selection is visible
+        let selected_row = selected / cols;
+        if selected_row < top_row {
+            top_row = selected_row;
+        } else if selected_row >= top_row + rows {
+            top_row = selected_row + 1 - rows;
+        }
+
+        draw_browser_bar(ctrl_window, &dir);
+        werase(window);
+        for (i, name) in names.iter().enumerate().skip(top_row * cols).take(rows * cols) {
+            let (row, col) = (i / cols - top_row, i % cols);
+            if i == selected {
+                wattron(window, A_REVERSE);
+            }
+            mvwaddstr(window, row as i32, (col * col_width) as i32, &fit(name, col_width - 1)).unwrap();
+            wattroff(window, A_REVERSE);
+        }
+        wrefresh(window);
+
+        match wget_wch(window) {
+            Some(WchResult::KeyCode(code)) => match code {
+                KEY_LEFT if selected > 0 => { selected -= 1; },
+                KEY_RIGHT if selected + 1 < entries.len() => { selected += 1; },
+                KEY_UP if selected >= cols => { selected -= cols; },
+                KEY_DOWN if selected + cols < entries.len() => { selected += cols; },
+                KEY_PPAGE => { selected = selected.saturating_sub(rows * cols); },
+                KEY_NPAGE if !entries.is_empty() => { selected = min(selected + rows * cols, entries.len() - 1); },
+                KEY_HOME => { selected = 0; },
+                KEY_END if !entries.is_empty() => { selected = entries.len() - 1; },
+                _ => { beep(); }
+            },
+            Some(WchResult::Char(char_code)) => match char::from_u32(char_code) {
+                Some('\r') if !entries.is_empty() => {
+                    let (name, is_dir) = &entries[selected];
+                    let path = if name == ".." { dir.parent().unwrap_or(&dir).to_path_buf() } else { dir.join(name) };
+                    if !is_dir {
+                        ret = Some(display_path(&path));
+                        break;
+                    }
+                    match read_entries(&path) {
+                        Some(new_entries) => {
+                            // Going up keeps the directory we came from selected
+                            let came_from = dir.file_name().and_then(|name| name.to_str()).map(|name| name.to_string());
+                            selected = if name == ".." {
+                                new_entries.iter().position(|(entry, _)| Some(entry) == came_from.as_ref()).unwrap_or(0)
+                            } else {
+                                0
+                            };
+                            dir = path;
+                            entries = new_entries;
+                            top_row = 0;
+                        },
+                        None => { beep(); } // Can't read it
+                    }
+                },
+                Some('\u{0003}') => { break; }, // Ctrl-C
+                _ => { beep(); }
+            },
+            None => {}
+        }
+    }
+
+    close_window(window, over);
+    curs_set(CURSOR_VISIBILITY::CURSOR_VERY_VISIBLE);
+    ret
+}
+
+// Helper functions
+
+fn draw_browser_bar(window: WINDOW, dir: &Path) {
+    // Shows the directory being browsed and the browser's keys in the control bar
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(window, &mut max_y, &mut max_x);
+
+    let title = fit(&format!("Browsing: {}", dir.display()), max_x as usize);
+    let ctrl_string = "[Enter] Open\t[Arrows] Move\t[^C] Cancel".to_string();
+    let ctrl_string_len = ctrl_string.len();
+    wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+    mvwaddstr(window, 0, 0, &title).unwrap();
+    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
+    wrefresh(window);
+}
+
+fn read_entries(dir: &Path) -> Option<Vec<(String, bool)>> {
+    // Gets a directory's entries as (name, is_dir), directories first
+    // ".." comes first, unless dir is the root
+    let mut entries: Vec<(String, bool)> = fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((entry.file_name().into_string().ok()?, entry.path().is_dir())))
+        .collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    if dir.parent().is_some() {
+        entries.insert(0, ("..".to_string(), true));
+    }
+    Some(entries)
+}
+
+fn display_path(path: &Path) -> String {
+    // Gets path relative to the working directory, if it's under it
+    match env::current_dir() {
+        Ok(cwd) => match path.strip_prefix(fs::canonicalize(cwd).unwrap_or_default()) {
+            Ok(relative) => relative.display().to_string(),
+            Err(_) => path.display().to_string()
+        },
+        Err(_) => path.display().to_string()
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    // Expands a leading ~ to the home directory, and an empty path to "."
+    if path.is_empty() {
+        return PathBuf::from(".");
+    }
+    match (path.strip_prefix('~'), env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => PathBuf::from(home + rest),
+        _ => PathBuf::from(path)
+    }
+}
+
+pub fn overlay_window(over: WINDOW) -> WINDOW {
+    // Creates a blank window covering the given one
+    let (mut y, mut x, mut height, mut width) = (0, 0, 0, 0);
+    getbegyx(over, &mut y, &mut x);
+    getmaxyx(over, &mut height, &mut width);
+    newwin(height, width, y, x)
+}
+
+fn column_width(names: &[String], width: usize) -> usize {
+    // Gets the width of a column of names, including the gap after it
+    let widest = names.iter().map(|name| str_width(name)).max().unwrap_or(0);
+    min(widest + 2, max(width, 1))
+}
+
+fn fit(string: &str, width: usize) -> String {
+    // Cuts string down to width columns, or pads it with spaces up to it
+    let mut out = String::new();
+    let mut used = 0;
+    for c in string.chars() {
+        let w = char_width(c);
+        if used + w > width {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    out + &" ".repeat(width - used)
+}
+
+fn common_prefix(names: &[String]) -> String {
+    // Gets the longest prefix shared by all the names
+    let mut prefix: Vec<char> = names[0].chars().collect();
+    for name in names.iter().skip(1) {
+        let shared = prefix.iter().zip(name.chars()).take_while(|(a, b)| **a == *b).count();
+        prefix.truncate(shared);
+    }
+    prefix.into_iter().collect()
+}
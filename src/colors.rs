@@ -1,10 +1,13 @@
 extern crate ncurses;
 use ncurses::*;
+use std::sync::Mutex;
 
 // Color constants
 // System colors
 pub static CP_HIGHLIGHT: i16 = 1;
 pub static CP_SEARCH: i16 = 2;
+pub static CP_ERROR: i16 = 3;
+pub static CP_LINE_NUMBER: i16 = 4;
 
 // Syntax-highlighting colors
 pub static CP_BLACK: i16 = 0x0010;
@@ -24,6 +27,8 @@ pub fn init_colors() {
 
     init_pair(CP_HIGHLIGHT, COLOR_BLACK, COLOR_WHITE);
     init_pair(CP_SEARCH, COLOR_BLACK, COLOR_YELLOW);
+    init_pair(CP_ERROR, COLOR_WHITE, COLOR_RED);
+    init_pair(CP_LINE_NUMBER, COLOR_YELLOW, -1);
 
     init_pair(CP_BLACK, COLOR_BLACK, COLOR_BLACK);
     init_pair(CP_RED, COLOR_RED, COLOR_BLACK);
@@ -34,3 +39,24 @@ pub fn init_colors() {
     init_pair(CP_CYAN, COLOR_CYAN, COLOR_BLACK);
     init_pair(CP_WHITE, COLOR_WHITE, COLOR_BLACK);
 }
+
+// Dynamically allocated pairs (used by nanorc colors, which can have any
+// foreground/background combination). Pairs are handed out from CP_DYNAMIC
+// on as combinations are first asked for, as far as the terminal has them
+static CP_DYNAMIC: i16 = 0x0020;
+static DYNAMIC_PAIRS: Mutex<Vec<(i16, i16)>> = Mutex::new(Vec::new()); // (fg, bg) of each pair from CP_DYNAMIC
+
+pub fn color_pair(fg: i16, bg: i16) -> i16 {
+    // Gets (and initializes) the color pair for the given colors
+    // Falls back to the default pair (0) once the terminal runs out of pairs
+    let mut pairs = DYNAMIC_PAIRS.lock().unwrap();
+    if let Some(i) = pairs.iter().position(|pair| *pair == (fg, bg)) {
+        return CP_DYNAMIC + i as i16;
+    }
+    let pair = CP_DYNAMIC + pairs.len() as i16;
+    if i32::from(pair) >= COLOR_PAIRS() || init_pair(pair, fg, bg) == ERR {
+        return 0;
+    }
+    pairs.push((fg, bg));
+    pair
+}
@@ -1,28 +1,668 @@
+// Config file loader and evaluator
+// The config language is a small lisp (see config-file-spec.txt)
 
+extern crate ncurses;
+use ncurses::*;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+use crate::colors;
+use crate::gapnc::LineNumbers;
+use crate::keymap;
+use crate::nanorc;
+use crate::syntax_highlighting::{HighlightRules, SyntaxHighlight};
+
+const CONFIG_FILE_NAME: &str = ".ranorc";
+const MAX_LOAD_DEPTH: usize = 16; // Guards against files that (indirectly) load themselves
 
 pub struct Config {
-    tabsize: usize
+    pub tabsize: usize,
+    pub linenumbers: LineNumbers,
+    pub highlights: Vec<SyntaxHighlight>,
+    pub bindings: Vec<(keymap::Key, Option<keymap::Command>)>, // In the order they're made, None unbinds
+    pub skipped: Vec<ConfigError> // Errors that only cost a rule, e.g. a bad regex in an included nanorc
+}
+
+// The buffer a config is being evaluated for; predicates are checked against this
+pub struct Target {
+    pub path: Option<PathBuf>,
+    pub first_line: String
 }
 
 enum Command {
-    Set(Attribute, Value)
+    Config(Predicate, Box<Command>), // Config(predicate, body)
+    List(Vec<Command>),
+    Set(Attribute, Value),
+    Highlight(SyntaxHighlight),
+    Load(PathBuf),
+    Bind(keymap::Key, Option<keymap::Command>),
+    Include(PathBuf) // nanorc file(s); the file name may be a glob
 }
 
 enum Attribute {
+    TabSize,
+    LineNumbers
 }
 
-enum Value {
+enum Predicate {
+    Constant(bool),
+    FileExt(Vec<String>), // Extensions, without the leading dot
+    FileName(Vec<(Regex, bool)>), // Exact names and globs compiled to anchored regexes, with whether to match the whole path
+    Shebang(Regex), // Matched against the interpreter part of a #! line
+    Magic(Regex), // Matched against the whole first line
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+    Not(Box<Predicate>)
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
     Number(usize),
     String(String),
-    Atom(String)
+    Atom(String),
+    List(Vec<Value>)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Syntax(usize, String), // Syntax(line, message)
+    Eval(String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Syntax(line, msg) => write!(f, "line {}: {}", line, msg),
+            ConfigError::Eval(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl Value {
+    fn describe(&self) -> String {
+        // Short description of the value, for error messages
+        match self {
+            Value::Number(n) => n.to_string(),
+            Value::String(s) => format!("{:?}", s),
+            Value::Atom(a) => a.clone(),
+            Value::List(items) => match items.first() {
+                Some(Value::Atom(head)) => format!("({} ...)", head),
+                _ => "(...)".to_string()
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn highlight_rules(&self) -> Option<HighlightRules> {
+        // Builds the highlighting rules set by the config, if there are any
+        if self.highlights.is_empty() {
+            None
+        } else {
+            Some(HighlightRules::new(self.highlights.clone()))
+        }
+    }
 }
 
 pub fn default() -> Config {
     Config {
-        tabsize: 4
+        tabsize: 4,
+        linenumbers: LineNumbers::Off,
+        highlights: Vec::new(),
+        bindings: Vec::new(),
+        skipped: Vec::new()
+    }
+}
+
+// Loading
+
+pub fn user_config_path() -> Option<PathBuf> {
+    // Location of the user's config file (~/.ranorc)
+    env::var_os("HOME").map(|home| Path::new(&home).join(CONFIG_FILE_NAME))
+}
+
+impl Target {
+    pub fn new(path: Option<PathBuf>, first_line: String) -> Target {
+        Target { path, first_line }
+    }
+
+    fn file_name(&self) -> Option<String> {
+        self.path.as_ref()?.file_name().map(|name| name.to_string_lossy().to_string())
+    }
+}
+
+pub fn load_user_config(target: &Target) -> Result<Config, ConfigError> {
+    // Loads ~/.ranorc for the given buffer, falling back to the defaults if it doesn't exist
+    let mut config = default();
+    if let Some(path) = user_config_path() {
+        if path.exists() {
+            load_into(&path, &mut config, target, 0)?;
+        }
+    }
+    Ok(config)
+}
+
+fn load_into(path: &Path, config: &mut Config, target: &Target, depth: usize) -> Result<(), ConfigError> {
+    // Reads, parses and evaluates the config file at path on top of config
+    if depth > MAX_LOAD_DEPTH {
+        return Err(ConfigError::Eval(format!("{}: too many nested loads", path.display())));
+    }
+    let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    let values = parse_config(contents.chars().collect())?;
+    for value in values.iter() {
+        let command = compile(value, path)?;
+        execute(&command, config, target, depth)?;
+    }
+    Ok(())
+}
+
+// Reader
+
+enum Token {
+    Open,
+    Close,
+    Str(String),
+    Word(String)
+}
+
+fn tokenize(config_buffer: &[char]) -> Result<Vec<(usize, Token)>, ConfigError> {
+    // Splits the config file into (line, token) pairs
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < config_buffer.len() {
+        let ch = config_buffer[i];
+        match ch {
+            '\n' => { line += 1; i += 1; },
+            c if c.is_whitespace() => { i += 1; }, // Includes form feeds, NBSP etc., which would otherwise make empty words
+            ';' | '#' => {
+                // Comment runs until the end of the line
+                while i < config_buffer.len() && config_buffer[i] != '\n' {
+                    i += 1;
+                }
+            },
+            '(' => { tokens.push((line, Token::Open)); i += 1; },
+            ')' => { tokens.push((line, Token::Close)); i += 1; },
+            '"' => {
+                let start_line = line;
+                let mut string = String::new();
+                i += 1;
+                loop {
+                    match config_buffer.get(i) {
+                        None => { return Err(ConfigError::Syntax(start_line, "unterminated string".to_string())); },
+                        Some('"') => { i += 1; break; },
+                        Some('\\') => {
+                            // Only quotes and backslashes are escaped; everything else is kept
+                            // as-is so that regexes can be written without double escaping
+                            match config_buffer.get(i + 1) {
+                                Some('"') => { string.push('"'); i += 2; },
+                                Some('\\') => { string.push('\\'); i += 2; },
+                                _ => { string.push('\\'); i += 1; }
+                            }
+                        },
+                        Some(c) => {
+                            if *c == '\n' {
+                                line += 1;
+                            }
+                            string.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push((start_line, Token::Str(string)));
+            },
+            _ => {
+                let mut word = String::new();
+                while i < config_buffer.len() && !config_buffer[i].is_whitespace() && !"()\";".contains(config_buffer[i]) {
+                    word.push(config_buffer[i]);
+                    i += 1;
+                }
+                tokens.push((line, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+pub fn parse_config(config_buffer: Vec<char>) -> Result<Vec<Value>, ConfigError> {
+    // Parses the config file and spits out a vector of top-level expressions
+    let tokens = tokenize(&config_buffer)?;
+    let mut stack: Vec<(usize, Vec<Value>)> = Vec::new(); // Lists that are still open, with their starting line
+    let mut values = Vec::new();
+
+    for (line, token) in tokens {
+        let value = match token {
+            Token::Open => {
+                stack.push((line, Vec::new()));
+                continue;
+            },
+            Token::Close => match stack.pop() {
+                Some((_, items)) => Value::List(items),
+                None => { return Err(ConfigError::Syntax(line, "unexpected ')'".to_string())); }
+            },
+            Token::Str(string) => Value::String(string),
+            Token::Word(word) => match word.parse::<usize>() {
+                Ok(n) => Value::Number(n),
+                Err(_) => Value::Atom(word)
+            }
+        };
+        match stack.last_mut() {
+            Some((_, items)) => { items.push(value); },
+            None => { values.push(value); }
+        }
+    }
+
+    if let Some((line, _)) = stack.pop() {
+        return Err(ConfigError::Syntax(line, "unclosed '('".to_string()));
+    }
+    Ok(values)
+}
+
+// Evaluator
+
+fn compile(value: &Value, source: &Path) -> Result<Command, ConfigError> {
+    // Turns a parsed expression into a command
+    let items = match value {
+        Value::List(items) => items,
+        _ => { return Err(ConfigError::Eval(format!("expected a command, got {}", value.describe()))); }
+    };
+    let (name, args) = match items.split_first() {
+        Some((Value::Atom(name), args)) => (name.as_str(), args),
+        _ => { return Err(ConfigError::Eval(format!("expected a command name in {}", value.describe()))); }
+    };
+
+    match name {
+        "config" => {
+            expect_args(name, args, 2)?;
+            Ok(Command::Config(compile_predicate(&args[0])?, Box::new(compile(&args[1], source)?)))
+        },
+        "list" => {
+            let mut commands = Vec::new();
+            for arg in args {
+                commands.push(compile(arg, source)?);
+            }
+            Ok(Command::List(commands))
+        },
+        "set" => {
+            expect_args(name, args, 2)?;
+            let attribute = match &args[0] {
+                Value::Atom(a) if a == "tabsize" => Attribute::TabSize,
+                Value::Atom(a) if a == "linenumbers" => Attribute::LineNumbers,
+                other => { return Err(ConfigError::Eval(format!("unknown attribute {}", other.describe()))); }
+            };
+            Ok(Command::Set(attribute, args[1].clone()))
+        },
+        "highlight" => Ok(Command::Highlight(compile_highlight(args)?)),
+        "region" => {
+            // (region "START" "END" COLOR OPTION ...), where the options are
+            // nested, (escape "REGEX") and (highlight ...) for inner rules
+            if args.len() < 3 {
+                return Err(ConfigError::Eval(format!("region expects at least 3 arguments, got {}", args.len())));
+            }
+            let start = compile_regex(&string_args(name, &args[0..1])?[0])?;
+            let end = compile_regex(&string_args(name, &args[1..2])?[0])?;
+            let mut region = SyntaxHighlight::region(start, end, color_arg(name, &args[2])?);
+            let mut inner = Vec::new();
+            for option in &args[3..] {
+                match option {
+                    Value::Atom(a) if a == "nested" => { region = region.nested(); },
+                    Value::List(items) => match items.split_first() {
+                        Some((Value::Atom(a), rest)) if a == "escape" => {
+                            expect_args(a, rest, 1)?;
+                            region = region.with_escape(compile_regex(&string_args(a, rest)?[0])?);
+                        },
+                        Some((Value::Atom(a), rest)) if a == "highlight" => { inner.push(compile_highlight(rest)?); },
+                        _ => { return Err(ConfigError::Eval(format!("unknown region option {}", option.describe()))); }
+                    },
+                    _ => { return Err(ConfigError::Eval(format!("unknown region option {}", option.describe()))); }
+                }
+            }
+            if !inner.is_empty() {
+                region = region.with_inner(inner);
+            }
+            Ok(Command::Highlight(region))
+        },
+        "load" => {
+            expect_args(name, args, 1)?;
+            match &args[0] {
+                Value::String(s) => Ok(Command::Load(resolve_path(s, source))),
+                other => Err(ConfigError::Eval(format!("load expects a path string, got {}", other.describe())))
+            }
+        },
+        "include" => {
+            expect_args(name, args, 1)?;
+            match &args[0] {
+                Value::String(s) => Ok(Command::Include(resolve_path(s, source))),
+                other => Err(ConfigError::Eval(format!("include expects a path string, got {}", other.describe())))
+            }
+        },
+        "bind" => {
+            expect_args(name, args, 2)?;
+            let command = match &args[1] {
+                Value::Atom(a) => keymap::parse_command(a).ok_or_else(|| ConfigError::Eval(format!("unknown editor command {}", a)))?,
+                other => { return Err(ConfigError::Eval(format!("bind expects a command name, got {}", other.describe()))); }
+            };
+            Ok(Command::Bind(key_arg(name, &args[0])?, Some(command)))
+        },
+        "unbind" => {
+            expect_args(name, args, 1)?;
+            Ok(Command::Bind(key_arg(name, &args[0])?, None))
+        },
+        _ => Err(ConfigError::Eval(format!("unknown command {}", name)))
     }
 }
 
-pub fn parse_config(config_buffer: Vec<char>) {
-    // Parses the config file and spits out a vector
+fn execute(command: &Command, config: &mut Config, target: &Target, depth: usize) -> Result<(), ConfigError> {
+    // Runs a command on the given config
+    match command {
+        Command::Config(predicate, body) => {
+            if eval_predicate(predicate, target) {
+                execute(body, config, target, depth)?;
+            }
+        },
+        Command::List(commands) => {
+            for command in commands {
+                execute(command, config, target, depth)?;
+            }
+        },
+        Command::Set(Attribute::TabSize, value) => match value {
+            Value::Number(n) if *n > 0 => { config.tabsize = *n; },
+            other => { return Err(ConfigError::Eval(format!("tabsize must be a positive number, got {}", other.describe()))); }
+        },
+        Command::Set(Attribute::LineNumbers, value) => match value {
+            Value::Atom(a) if a == "off" => { config.linenumbers = LineNumbers::Off; },
+            Value::Atom(a) if a == "absolute" => { config.linenumbers = LineNumbers::Absolute; },
+            Value::Atom(a) if a == "relative" => { config.linenumbers = LineNumbers::Relative; },
+            Value::Atom(a) if a == "hybrid" => { config.linenumbers = LineNumbers::Hybrid; },
+            other => { return Err(ConfigError::Eval(format!("linenumbers must be off, absolute, relative or hybrid, got {}", other.describe()))); }
+        },
+        Command::Highlight(highlight) => {
+            config.highlights.push(highlight.clone());
+        },
+        Command::Bind(key, command) => {
+            config.bindings.push((*key, *command));
+        },
+        Command::Load(path) => {
+            load_into(path, config, target, depth + 1)?;
+        },
+        Command::Include(pattern) => {
+            let mut syntaxes = Vec::new();
+            for path in expand_glob(pattern)? {
+                syntaxes.extend(nanorc::load_nanorc(&path, &mut config.skipped)?);
+            }
+            if let Some(syntax) = nanorc::find_syntax(syntaxes, target) {
+                config.highlights.extend(syntax.into_rules());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn compile_highlight(args: &[Value]) -> Result<SyntaxHighlight, ConfigError> {
+    // Compiles the arguments of (highlight "REGEX" COLOR)
+    expect_args("highlight", args, 2)?;
+    let regex = match &args[0] {
+        Value::String(s) => compile_regex(s)?,
+        other => { return Err(ConfigError::Eval(format!("highlight expects a regex string, got {}", other.describe()))); }
+    };
+    Ok(SyntaxHighlight::new(regex, color_arg("highlight", &args[1])?))
+}
+
+fn color_arg(name: &str, arg: &Value) -> Result<u64, ConfigError> {
+    match arg {
+        Value::Atom(a) => parse_color(a).ok_or_else(|| ConfigError::Eval(format!("unknown color {}", a))),
+        other => Err(ConfigError::Eval(format!("{} expects a color, got {}", name, other.describe())))
+    }
+}
+
+fn key_arg(name: &str, arg: &Value) -> Result<keymap::Key, ConfigError> {
+    // Keys can be written as strings or atoms, e.g. "^Z", M-b or "("
+    match arg {
+        Value::String(s) | Value::Atom(s) => keymap::parse_key(s).ok_or_else(|| ConfigError::Eval(format!("unknown key {:?}", s))),
+        other => Err(ConfigError::Eval(format!("{} expects a key, got {}", name, other.describe())))
+    }
+}
+
+fn compile_predicate(value: &Value) -> Result<Predicate, ConfigError> {
+    // Turns a parsed expression into a predicate
+    let items = match value {
+        Value::Atom(a) if a == "true" => { return Ok(Predicate::Constant(true)); },
+        Value::Atom(a) if a == "false" => { return Ok(Predicate::Constant(false)); },
+        Value::List(items) => items,
+        other => { return Err(ConfigError::Eval(format!("unknown predicate {}", other.describe()))); }
+    };
+    let (name, args) = match items.split_first() {
+        Some((Value::Atom(name), args)) => (name.as_str(), args),
+        _ => { return Err(ConfigError::Eval(format!("expected a predicate name in {}", value.describe()))); }
+    };
+
+    match name {
+        "fileext?" => {
+            let exts = string_args(name, args)?;
+            Ok(Predicate::FileExt(exts.iter().map(|ext| ext.trim_start_matches('.').to_string()).collect()))
+        },
+        "filename?" => {
+            let mut patterns = Vec::new();
+            for pattern in string_args(name, args)? {
+                patterns.push((compile_regex(&glob_to_regex(&pattern))?, pattern.contains('/')));
+            }
+            Ok(Predicate::FileName(patterns))
+        },
+        "shebang?" => {
+            expect_args(name, args, 1)?;
+            Ok(Predicate::Shebang(compile_regex(&string_args(name, args)?[0])?))
+        },
+        "magic?" => {
+            expect_args(name, args, 1)?;
+            Ok(Predicate::Magic(compile_regex(&string_args(name, args)?[0])?))
+        },
+        "and" | "or" => {
+            let mut predicates = Vec::new();
+            for arg in args {
+                predicates.push(compile_predicate(arg)?);
+            }
+            if name == "and" {
+                Ok(Predicate::And(predicates))
+            } else {
+                Ok(Predicate::Or(predicates))
+            }
+        },
+        "not" => {
+            expect_args(name, args, 1)?;
+            Ok(Predicate::Not(Box::new(compile_predicate(&args[0])?)))
+        },
+        _ => Err(ConfigError::Eval(format!("unknown predicate {}", name)))
+    }
+}
+
+fn eval_predicate(predicate: &Predicate, target: &Target) -> bool {
+    // Decides whether a config block applies to the target
+    match predicate {
+        Predicate::Constant(b) => *b,
+        Predicate::FileExt(exts) => match target.path.as_ref().and_then(|path| path.extension()) {
+            Some(ext) => exts.iter().any(|e| ext.to_string_lossy() == *e),
+            None => false
+        },
+        Predicate::FileName(patterns) => {
+            // Patterns containing a slash are matched against the whole path,
+            // everything else against just the file name
+            let path = match &target.path {
+                Some(path) => path.to_string_lossy().to_string(),
+                None => { return false; }
+            };
+            let name = target.file_name().unwrap_or_default();
+            patterns.iter().any(|(re, whole_path)| {
+                if *whole_path { re.is_match(&path) } else { re.is_match(&name) }
+            })
+        },
+        Predicate::Shebang(re) => match target.first_line.strip_prefix("#!") {
+            Some(interpreter) => re.is_match(interpreter),
+            None => false
+        },
+        Predicate::Magic(re) => re.is_match(&target.first_line),
+        Predicate::And(predicates) => predicates.iter().all(|p| eval_predicate(p, target)),
+        Predicate::Or(predicates) => predicates.iter().any(|p| eval_predicate(p, target)),
+        Predicate::Not(p) => !eval_predicate(p, target)
+    }
+}
+
+fn string_args(name: &str, args: &[Value]) -> Result<Vec<String>, ConfigError> {
+    // Checks that all arguments are strings (and that there is at least one)
+    if args.is_empty() {
+        return Err(ConfigError::Eval(format!("{} expects at least one argument", name)));
+    }
+    args.iter().map(|arg| match arg {
+        Value::String(s) => Ok(s.clone()),
+        other => Err(ConfigError::Eval(format!("{} expects strings, got {}", name, other.describe())))
+    }).collect()
+}
+
+fn compile_regex(pattern: &str) -> Result<Regex, ConfigError> {
+    Regex::new(pattern).map_err(|e| ConfigError::Eval(format!("bad regex {:?}: {}", pattern, regex_error_reason(&e))))
+}
+
+pub fn regex_error_reason(e: &regex::Error) -> String {
+    // Gets what's wrong with a regex in one line, for messages
+    // (the error's last line; the ones before it point at where)
+    e.to_string().lines().last().unwrap_or_default().trim_start_matches("error: ").to_string()
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    // Translates a shell glob (*, ?, [...]) into an anchored regex
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            },
+            _ => regex.push_str(&regex::escape(&ch.to_string()))
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn expect_args(name: &str, args: &[Value], n: usize) -> Result<(), ConfigError> {
+    if args.len() != n {
+        return Err(ConfigError::Eval(format!("{} expects {} argument(s), got {}", name, n, args.len())));
+    }
+    Ok(())
+}
+
+fn resolve_path(path: &str, source: &Path) -> PathBuf {
+    // Expands ~ and makes relative paths relative to the file doing the loading
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = env::var_os("HOME") {
+            return Path::new(&home).join(rest);
+        }
+    }
+    let path = Path::new(path);
+    if path.is_relative() {
+        if let Some(dir) = source.parent() {
+            return dir.join(path);
+        }
+    }
+    path.to_path_buf()
+}
+
+fn expand_glob(pattern: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+    // Expands a glob in the file name part of a path (e.g. /usr/share/nano/*.nanorc)
+    let file_name = match pattern.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => { return Ok(vec![pattern.to_path_buf()]); }
+    };
+    if !file_name.contains(['*', '?', '[']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+
+    let dir = pattern.parent().unwrap_or(Path::new("."));
+    let re = compile_regex(&glob_to_regex(&file_name))?;
+    let entries = fs::read_dir(dir).map_err(|e| ConfigError::Io(dir.to_path_buf(), e))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| re.is_match(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn parse_color(name: &str) -> Option<u64> {
+    // Maps a color name to a color pair; "bright" or "bold" prefixes make the color bold
+    let (base, bold) = match name.strip_prefix("bright").or_else(|| name.strip_prefix("bold-")) {
+        Some(base) => (base, true),
+        None => (name, false)
+    };
+    let pair = match base {
+        "black" => colors::CP_BLACK,
+        "red" => colors::CP_RED,
+        "green" => colors::CP_GREEN,
+        "yellow" => colors::CP_YELLOW,
+        "blue" => colors::CP_BLUE,
+        "magenta" => colors::CP_MAGENTA,
+        "cyan" => colors::CP_CYAN,
+        "white" => colors::CP_WHITE,
+        _ => { return None; }
+    };
+    if bold {
+        Some(COLOR_PAIR(pair) | A_BOLD)
+    } else {
+        Some(COLOR_PAIR(pair))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<(usize, String)> {
+        // Tokenizes text, showing each token as (line, text)
+        tokenize(&text.chars().collect::<Vec<char>>()).unwrap().into_iter().map(|(line, token)| {
+            let text = match token {
+                Token::Open => "(".to_string(),
+                Token::Close => ")".to_string(),
+                Token::Str(string) => format!("{:?}", string),
+                Token::Word(word) => word
+            };
+            (line, text)
+        }).collect()
+    }
+
+    #[test]
+    fn tokenize_words() {
+        assert_eq!(tokens("(set tabsize 4) ; comment\n(bind \"^A\" undo)"), vec![
+            (1, "(".to_string()), (1, "set".to_string()), (1, "tabsize".to_string()), (1, "4".to_string()), (1, ")".to_string()),
+            (2, "(".to_string()), (2, "bind".to_string()), (2, "\"^A\"".to_string()), (2, "undo".to_string()), (2, ")".to_string())
+        ]);
+    }
+
+    #[test]
+    fn tokenize_other_whitespace() {
+        // Form feed, vertical tab and NBSP separate words like spaces do
+        assert_eq!(tokens("\x0c(set\x0btabsize\u{00A0}4)\r\n"), vec![
+            (1, "(".to_string()), (1, "set".to_string()), (1, "tabsize".to_string()), (1, "4".to_string()), (1, ")".to_string())
+        ]);
+        assert_eq!(tokens("\u{2003}\n\x0c"), vec![]);
+    }
 }
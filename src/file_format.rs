@@ -0,0 +1,98 @@
+// How a file's bytes become the buffer's text and back. The encoding is
+// detected when the file is opened and used again when it's saved; line
+// endings are kept in the text as they are, so they're only reported
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Utf8,
+    Latin1 // Anything that isn't UTF-8: every byte is one char, so the file saves back unchanged
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Mixed
+}
+
+impl Encoding {
+    pub fn decode(bytes: Vec<u8>) -> (String, Encoding) {
+        // Reads a file's contents as UTF-8 if they are, or else as Latin-1
+        match String::from_utf8(bytes) {
+            Ok(text) => (text, Encoding::Utf8),
+            Err(e) => (e.into_bytes().into_iter().map(char::from).collect(), Encoding::Latin1)
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Option<Vec<u8>> {
+        // Gets the bytes to save, or None if text has characters the encoding can't hold
+        match self {
+            Encoding::Utf8 => Some(text.as_bytes().to_vec()),
+            Encoding::Latin1 => text.chars().map(|c| u8::try_from(c).ok()).collect()
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "Latin-1"
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn detect(text: &str) -> LineEnding {
+        // Tells the line ending style by how every line ends (LF if there's only one line)
+        let lines = text.matches('\n').count();
+        let crlf_lines = text.matches("\r\n").count();
+        match crlf_lines {
+            0 => LineEnding::Lf,
+            n if n == lines => LineEnding::Crlf,
+            _ => LineEnding::Mixed
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::Crlf => "CRLF",
+            LineEnding::Mixed => "Mixed"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_is_kept() {
+        let (text, encoding) = Encoding::decode("日本語 café\n".as_bytes().to_vec());
+        assert_eq!(encoding, Encoding::Utf8);
+        assert_eq!(text, "日本語 café\n");
+    }
+
+    #[test]
+    fn other_bytes_are_latin1() {
+        let bytes = b"caf\xe9 \xff\n".to_vec();
+        let (text, encoding) = Encoding::decode(bytes.clone());
+        assert_eq!(encoding, Encoding::Latin1);
+        assert_eq!(text, "café ÿ\n");
+        assert_eq!(encoding.encode(&text), Some(bytes));
+    }
+
+    #[test]
+    fn latin1_cant_hold_everything() {
+        assert_eq!(Encoding::Latin1.encode("naïve"), Some(b"na\xefve".to_vec()));
+        assert_eq!(Encoding::Latin1.encode("日本"), None);
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(LineEnding::detect(""), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\nb\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\r\nb"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\nb\r\n"), LineEnding::Mixed);
+    }
+}
@@ -1,6 +1,3 @@
-use std::fs;
-use std::io::Read;
-
 pub struct GapBuffer {
     pub buffer: Vec<char>,
     pub gap_position: usize,
@@ -12,12 +9,10 @@ pub struct GapBuffer {
 }
 
 impl GapBuffer {
-    pub fn new_from_file(mut file: fs::File, gap_size: usize) -> GapBuffer {
-        // Read a file into a GapBuffer, with a certain starting gap size and
-        // with the gap at the very beginning
+    pub fn new_from_string(buffer_str: &str, gap_size: usize) -> GapBuffer {
+        // Creates a GapBuffer holding the given text, with a certain
+        // starting gap size and with the gap at the very beginning
         let mut buffer =  Vec::<char>::new();
-        let mut buffer_str = String::new();
-        file.read_to_string(&mut buffer_str);
 
         let mut n_lines = 0;
         let mut offset = 0;
@@ -155,6 +150,10 @@ impl GapBuffer {
         assert!(left <= self.len());
         assert!(right <= self.len());
 
+        if self.len() == 0 {
+            return Vec::new();
+        }
+
         // Don't count the very last selectable character
         let l = if left == self.len() { self.len() - 1 } else { left };
         let r = if right == self.len() { self.len() - 1 } else { right };
@@ -178,6 +177,10 @@ impl GapBuffer {
         assert!(left <= self.len());
         assert!(right <= self.len());
 
+        if self.len() == 0 {
+            return Vec::new();
+        }
+
         // Don't count the very last selectable character
         let l = if left == self.len() { self.len() - 1 } else { left };
         let r = if right == self.len() { self.len() - 1 } else { right };
@@ -247,6 +250,34 @@ impl GapBuffer {
         pointer
     }
 
+    pub fn line_of(&self, idx: usize) -> usize {
+        // Gets the (0-indexed) line number of idx, by counting
+        // newlines between it and the gap
+        assert!(idx <= self.len());
+        if idx < self.gap_position {
+            self.current_line - (idx..self.gap_position).filter(|i| self.get(*i) == Some(&'\n')).count()
+        } else {
+            self.current_line + (self.gap_position..idx).filter(|i| self.get(*i) == Some(&'\n')).count()
+        }
+    }
+
+    pub fn line_start(&self, n: usize) -> usize {
+        // Gets the index of the first character of line n,
+        // seeking from the line the gap is on
+        assert!(n <= self.n_lines);
+        let mut pointer = self.get_left_edge(self.gap_position);
+        let mut line = self.current_line;
+        while line > n {
+            pointer = self.get_left_edge(pointer - 1);
+            line -= 1;
+        }
+        while line < n {
+            pointer = self.get_right_edge(pointer) + 1;
+            line += 1;
+        }
+        pointer
+    }
+
     pub fn seek_back_n_lines(&self, start: usize, n_lines: usize) -> usize {
         // Seeks back n actual lines, and returns the left edge of
         // the very first line
@@ -6,7 +6,6 @@ use std::char;
 use ncurses::*;
 use std::env;
 use std::io::Read;
-use std::fs;
 use std::path::Path;
 use std::cmp::{min, max};
 use regex::Regex;
@@ -15,12 +14,14 @@ use crate::gap_buffer;
 use crate::colors;
 use crate::undo;
 use crate::syntax_highlighting;
+use crate::interval_tree::IntervalTree;
+use crate::search;
 
 type WindowYX = (usize, usize);
 type Range = (usize, usize); // Dijkstra range: [a, b)
 
 const INIT_GAP_SIZE: usize = 1024; // This is probably good enough for us to last us for a while
-const TAB_SIZE: usize = 4; // Move this into a config file soon
+const TAB_SIZE: usize = 4; // Default; overridden by `set tabsize` in the config file
 
 // Enum for increment/decrement - used to adjust highlight regions
 pub enum Adjust {
@@ -28,14 +29,26 @@ pub enum Adjust {
     Decrement(usize)
 }
 
+// What the gutter shows next to each line
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineNumbers {
+    Off, // No gutter
+    Absolute, // Each line's own number
+    Relative, // Distance from the cursor's line
+    Hybrid // Relative, except the cursor's line, which shows its own number
+}
+
 // Gap Editor
 pub struct GapEditor {
     buffer: gap_buffer::GapBuffer,
     // Cursors
     frame_cursor: usize, // Text Cursor is stored in buffer; Text Cursor = Display Cursor
     // Basic editor fields
-    size: WindowYX,
+    size: WindowYX, // Size of the text area, which is the window less the gutter
     window: WINDOW,
+    // Line number gutter
+    line_numbers: LineNumbers,
+    gutter_width: usize,
     // Smart cursor fields
     smart_cursor_flag: bool,
     smart_cursor_pos: usize,
@@ -51,25 +64,28 @@ pub struct GapEditor {
     // Save flag
     pub save_flag: bool,
     // Search highlighting
-    search_hits: Vec<Range>,
+    search_hits: IntervalTree<()>,
     // History
     history: Vec<undo::ActionGroup>,
     redo_history: Vec<undo::ActionGroup>,
     // Syntax Highlighting
-    highlight_rules: Option<syntax_highlighting::HighlightRules>,
-    regex_tree: Option<syntax_highlighting::PaintTree>,
-    recompile_regex_tree_flag: bool,
+    highlighter: Option<syntax_highlighting::Highlighter>,
     // Other configurations
     tab_size: usize
 }
 
 impl GapEditor {
-    pub fn from_file(file: fs::File, window: WINDOW) -> GapEditor {
-        // Creates a new GapEditor from a file
-        let buffer = gap_buffer::GapBuffer::new_from_file(file, INIT_GAP_SIZE);
+    pub fn from_string(text: &str, window: WINDOW) -> GapEditor {
+        // Creates a new GapEditor holding the given text
+        let buffer = gap_buffer::GapBuffer::new_from_string(text, INIT_GAP_SIZE);
         GapEditor::from_buffer(buffer, window)
     }
 
+    pub fn empty(window: WINDOW) -> GapEditor {
+        // Creates a new GapEditor with nothing in it
+        GapEditor::from_string("", window)
+    }
+
     pub fn from_buffer(buffer: gap_buffer::GapBuffer, window: WINDOW) -> GapEditor {
         // Creates a new GapEditor from the provided GapBuffer
         let size = get_window_dimensions(window);
@@ -79,6 +95,8 @@ impl GapEditor {
             frame_cursor: 0,
             size: size,
             window: window,
+            line_numbers: LineNumbers::Off,
+            gutter_width: 0,
             smart_cursor_flag: false,
             smart_cursor_pos: 0,
             select_mode_flag: false,
@@ -89,34 +107,65 @@ impl GapEditor {
             clipboard: Vec::<Vec<char>>::new(),
             clipboard_cursor: None,
             save_flag: true,
-            search_hits: Vec::<Range>::new(),
+            search_hits: IntervalTree::new(),
             history: Vec::<undo::ActionGroup>::new(),
             redo_history: Vec::<undo::ActionGroup>::new(),
-            highlight_rules: None,
-            regex_tree: None,
-            recompile_regex_tree_flag: true,
+            highlighter: None,
             tab_size: TAB_SIZE
         }
     }
 
+    pub fn resize(&mut self) {
+        // Picks up the window's new size, keeping the cursor in view
+        self.fit_gutter();
+        self.move_cursor_to();
+    }
+
+    pub fn set_line_numbers(&mut self, line_numbers: LineNumbers) {
+        self.line_numbers = line_numbers;
+        self.fit_gutter();
+    }
+
+    pub fn line_numbers(&self) -> LineNumbers {
+        self.line_numbers
+    }
+
+    fn fit_gutter(&mut self) {
+        // Sizes the gutter for the number of lines, and the text area to the rest of the window
+        let (height, window_width) = get_window_dimensions(self.window);
+        let digits = self.buffer.n_lines.to_string().len();
+        self.gutter_width = match self.line_numbers {
+            LineNumbers::Off => 0,
+            _ if digits + 2 > window_width => 0, // No room for it
+            _ => digits + 1 // The numbers and a space after them
+        };
+
+        let size = (height, window_width - self.gutter_width);
+        if size != self.size {
+            self.size = size;
+            // The frame could have started partway through a line that now wraps differently
+            self.frame_cursor = self.buffer.get_left_edge(self.frame_cursor);
+        }
+    }
+
     pub fn set_highlight_rules(&mut self, highlight_rules: syntax_highlighting::HighlightRules) {
-        self.highlight_rules = Some(highlight_rules);
+        self.highlighter = Some(syntax_highlighting::Highlighter::new(highlight_rules, self.buffer.n_lines + 1));
+    }
+
+    pub fn clear_highlight_rules(&mut self) {
+        self.highlighter = None;
+    }
+
+    pub fn set_tab_size(&mut self, tab_size: usize) {
+        assert!(tab_size > 0);
+        self.tab_size = tab_size;
     }
 
     pub fn display_at_frame_cursor(&mut self) {
         // Starts with the char at start, and outputs all characters that will fit in window
+        self.fit_gutter(); // The number of lines may have changed
         let start = self.frame_cursor;
         let (height, width) = self.size;
-        let mut start_x = 0;
-        let mut start_y = 0;
-        getyx(self.window, &mut start_y, &mut start_x);
-
-        wmove(self.window, 0, 0);
-
-        let mut cur_y = 0;
-        let mut cur_x = 0;
-        let mut new_y = 0;
-        let mut new_x = 0;
 
         // If select mode is on, get the range on which we need to highlight
         let (lmark, rmark) = if self.select_mode_flag {
@@ -133,75 +182,80 @@ impl GapEditor {
             (0, 0)
         };
 
-        // Compute highlight regex
-        let buffer = self.buffer.export();
-        let right_bound = (&buffer).len();
-        if self.recompile_regex_tree_flag {
-            match &self.highlight_rules {
-                Some(highlight_rules) => {
-                    let paint_vector = highlight_rules.highlight_region(buffer, 0, right_bound);
-                    let new_paint_tree = syntax_highlighting::paint_tree_from_vecs(paint_vector);
-                    self.regex_tree = Some(new_paint_tree);
-                    self.recompile_regex_tree_flag = false;
-                },
-                _ => {}
-            }
+        // Bring the highlighting of the visible lines up to date
+        let mut line = self.buffer.line_of(start);
+        let mut col = start - self.buffer.get_left_edge(start);
+        if let Some(ref mut highlighter) = self.highlighter {
+            highlighter.refresh(&self.buffer, line + height);
         }
 
-        /*
-        let maybe_paint_regions = match &self.highlight_rules {
-            Some(highlight_rules) => {
-                if self.recompile_regex_tree_flag {
-                    Some(highlight_rules.highlight_region(self.buffer.export(), start, right_bound))
-                } else {
-                    None
-                }
-            },
-            None => None
-        };
-        */
-
-        // Display the characters
+        // Display the characters, wrapping lines at the text area's width
+        let mut cur_y = 0;
+        let mut cur_x = 0;
+        self.draw_gutter(cur_y, if col == 0 { Some(line) } else { None });
         for i in start..self.buffer.len() {
             if let Some(ch) = self.buffer.get(i) {
                 match ch {
                     '\n' => {
-                        getyx(self.window, &mut cur_y, &mut cur_x);
-                        wmove(self.window, cur_y + 1, 0);
+                        cur_y += 1;
+                        cur_x = 0;
+                        line += 1;
+                        col = 0;
+                        if cur_y == height {
+                            break;
+                        }
+                        self.draw_gutter(cur_y, Some(line));
                     },
                     _ => {
                         if self.select_mode_flag && lmark <= i && i <= rmark {
-                            waddch_with_highlight(self.window, *ch as chtype);
+                            waddch_with_highlight(self.window, *ch);
                         } else if self.index_in_search_hits(i) {
-                            waddch_with_search(self.window, *ch as chtype);
-                        } else if let Some(ref regex_tree) = self.regex_tree {
-                            /*
-                            if let Some(color) = syntax_highlighting::Paint::find_match(&paint_regions, i) {
-                                waddch_with_color(self.window, *ch as chtype, color);
-                            } else {
-                                waddch(self.window, *ch as chtype);
-                            }
-                            */
-                            if let Some(color) = syntax_highlighting::paint_tree_get_color(regex_tree, i) {
-                                waddch_with_color(self.window, *ch as chtype, color);
+                            waddch_with_search(self.window, *ch);
+                        } else if let Some(ref highlighter) = self.highlighter {
+                            if let Some(color) = highlighter.get_color(line, col) {
+                                waddch_with_color(self.window, *ch, color);
                             } else {
-                                waddch(self.window, *ch as chtype);
+                                wadd_char(self.window, *ch);
                             }
                         } else {
-                            waddch(self.window, *ch as chtype);
+                            wadd_char(self.window, *ch);
+                        }
+                        col += 1;
+                        cur_x += 1;
+                        if cur_x == width {
+                            // Continue the line on the next row
+                            cur_y += 1;
+                            cur_x = 0;
+                            if cur_y == height {
+                                break;
+                            }
+                            self.draw_gutter(cur_y, None);
                         }
                     }
                 }
-                getyx(self.window, &mut cur_y, &mut cur_x);
-                if cur_y == new_y && cur_x == new_x {
-                    break;
-                }
-                new_y = cur_y;
-                new_x = cur_x;
             }
         }
     }
 
+    fn draw_gutter(&self, y: usize, line: Option<usize>) {
+        // Draws the line number for a row, or leaves it blank if the row continues a wrapped line
+        // Leaves the ncurses cursor where the row's text starts
+        if self.gutter_width == 0 {
+            wmove(self.window, y as i32, 0);
+            return;
+        }
+        let current_line = self.buffer.current_line;
+        let number = match (line, self.line_numbers) {
+            (None, _) | (_, LineNumbers::Off) => String::new(),
+            (Some(line), LineNumbers::Absolute) => (line + 1).to_string(),
+            (Some(line), LineNumbers::Hybrid) if line == current_line => (line + 1).to_string(),
+            (Some(line), _) => line.abs_diff(current_line).to_string()
+        };
+        wattron(self.window, COLOR_PAIR(colors::CP_LINE_NUMBER));
+        mvwaddstr(self.window, y as i32, 0, &format!("{:>1$} ", number, self.gutter_width - 1)).unwrap();
+        wattroff(self.window, COLOR_PAIR(colors::CP_LINE_NUMBER));
+    }
+
     fn get_frame_bound(&self) -> usize {
         // Computes the size of the displayed window
         // starting from the frame cursor, and returns
@@ -283,10 +337,10 @@ impl GapEditor {
                 // Text cursor is out of frame
                 if let Some(new_pos) = self.put_on_last_line() {
                     let (new_y, new_x) = new_pos;
-                    wmove(self.window, new_y, new_x);
+                    wmove(self.window, new_y, self.gutter_width as i32 + new_x);
                 }
             } else {
-                wmove(self.window, new_y, new_x);
+                wmove(self.window, new_y, self.gutter_width as i32 + new_x);
             }
         }
     }
@@ -313,10 +367,54 @@ impl GapEditor {
         Some((cur_y as i32, cur_x as i32))
     }
 
+    fn put_on_row(&mut self, row: usize) {
+        // Moves the frame cursor so that the text cursor is shown on the given row,
+        // or as close to it as the top of the buffer allows
+        let (_, width) = self.size;
+        let left_edge = self.buffer.get_left_edge(self.buffer.gap_position);
+        let mut frame_cursor = left_edge + (self.buffer.gap_position - left_edge) / width * width;
+        for _ in 0..row {
+            match self.buffer.get_prev_display_line_head(frame_cursor, width) {
+                Some(prev) => { frame_cursor = prev; },
+                None => { break; }
+            }
+        }
+        self.frame_cursor = frame_cursor;
+    }
+
     pub fn pos(&self) -> usize {
         self.buffer.gap_position
     }
 
+    pub fn move_to(&mut self, pos: usize) {
+        // Moves the text cursor to pos
+        self.buffer.move_gap(pos);
+        self.move_cursor_to();
+    }
+
+    pub fn cursor_line_col(&self) -> (usize, usize) {
+        // Gets the text cursor's line and column, counting from 1
+        let pos = self.buffer.gap_position;
+        (self.buffer.current_line + 1, pos - self.buffer.get_left_edge(pos) + 1)
+    }
+
+    pub fn line_count(&self) -> usize {
+        // Counts the lines, including the empty one after a final newline
+        self.buffer.n_lines + 1
+    }
+
+    pub fn first_line(&self) -> String {
+        // Gets the contents of the first line (used for shebang/magic detection)
+        let mut line = String::new();
+        for i in 0..self.buffer.len() {
+            match self.buffer.get(i) {
+                Some('\n') | None => { break; },
+                Some(ch) => { line.push(*ch); }
+            }
+        }
+        line
+    }
+
     // Arrow keys
     pub fn scroll_down(&mut self) {
         // Handle the cursor changes for scrolling down
@@ -405,7 +503,7 @@ impl GapEditor {
         // If ncurses cursor is at the bottom right corner, or on the bottom line
         // and at the end of the display line, then try to scroll the entire viewframe
         // down one line
-        if cursor_end(self.window, height, width) || cursor_bottom(self.window, height) && is_right_edge {
+        if cursor_end(self.window, height, self.gutter_width + width) || cursor_bottom(self.window, height) && is_right_edge {
             if let Some(new_frame_cursor) = self.buffer.get_next_display_line_head(self.frame_cursor, width) {
                 self.frame_cursor = new_frame_cursor;
             }
@@ -422,7 +520,7 @@ impl GapEditor {
             self.buffer.move_gap(self.buffer.gap_position - 1);
         }
 
-        if cursor_beginning(self.window) || cursor_top(self.window) && is_left_edge {
+        if cursor_beginning(self.window, self.gutter_width) || cursor_top(self.window) && is_left_edge {
             if let Some(new_frame_cursor) = self.buffer.get_prev_display_line_head(self.frame_cursor, width) {
                 self.frame_cursor = new_frame_cursor;
             }
@@ -451,8 +549,10 @@ impl GapEditor {
         self.fix_regions(Adjust::Increment(1));
 
         let start_gap_position = self.buffer.gap_position; // for history
+        let line = self.buffer.current_line;
 
         self.buffer.insert(character);
+        self.highlight_edit(line, 0, (character == '\n') as usize);
         self.move_cursor_to();
 
         let end_gap_position = self.buffer.gap_position; // for history
@@ -484,8 +584,10 @@ impl GapEditor {
         self.fix_regions(Adjust::Increment(1));
 
         let start_gap_position = self.buffer.gap_position; // for history        
+        let line = self.buffer.current_line;
 
         self.buffer.insert('\n');
+        self.highlight_edit(line, 0, 1);
         self.move_cursor_to();
 
         let end_gap_position = self.buffer.gap_position; // for history
@@ -534,24 +636,48 @@ impl GapEditor {
             }
         }
         */
-        self.fix_regions(Adjust::Decrement(1));
-
         let start_gap_position = self.buffer.gap_position;
 
+        let ch = self.pop_char(redo)?;
+
+        let end_gap_position = self.buffer.gap_position;
+
+        Some(undo::ActionGroup::Singleton(undo::Action::Delete(start_gap_position, ch, end_gap_position)))
+    }
+
+    pub fn delete(&mut self, redo: bool) -> Option<undo::ActionGroup> {
+        // Deletes the character under the cursor
+        self.smart_cursor_flag = false;
+        let gap_position = self.buffer.gap_position;
+        if gap_position == self.buffer.len() {
+            beep(); // Trying to delete at tail
+            return None;
+        }
+
+        self.buffer.move_gap(gap_position + 1);
+        let ch = self.pop_char(redo)?;
+
+        Some(undo::ActionGroup::Singleton(undo::Action::DeleteForward(gap_position, ch)))
+    }
+
+    fn pop_char(&mut self, redo: bool) -> Option<char> {
+        // Removes the character behind the text cursor, for backspace and delete
+        self.fix_regions(Adjust::Decrement(1));
+
         let ch: char = match self.buffer.pop() {
             Some(c) => {c},
             None => { beep(); return None; } // Trying to delete at head
         }; // Capture the deleted char
+        let line = self.buffer.current_line;
+        self.highlight_edit(line, (ch == '\n') as usize, 0);
         self.move_cursor_to();
 
-        let end_gap_position = self.buffer.gap_position;
-
         self.set_save(); // Modified the buffer, set flag
 
         if !redo {
             self.clear_redo_history();
         }
-        Some(undo::ActionGroup::Singleton(undo::Action::Delete(start_gap_position, ch, end_gap_position)))
+        Some(ch)
     }
 
     // Advanced navigation
@@ -670,6 +796,81 @@ impl GapEditor {
         }
     }
 
+    pub fn page_down(&mut self) {
+        // Scrolls down a screen's worth, keeping the cursor on the same row of the window
+        // The column is kept by scroll_down, through the smart cursor
+        let (height, _) = self.size;
+        let row = getcury(self.window) as usize;
+        let start = self.buffer.gap_position;
+        for _ in 0..height {
+            let before = self.buffer.gap_position;
+            self.scroll_down();
+            if self.buffer.gap_position == before {
+                break;
+            }
+        }
+        if self.buffer.gap_position == start {
+            // Already on the last line
+            beep();
+            return;
+        }
+        self.put_on_row(row);
+        self.move_cursor_to();
+    }
+
+    pub fn page_up(&mut self) {
+        // Scrolls up a screen's worth, keeping the cursor on the same row of the window
+        let (height, _) = self.size;
+        let row = getcury(self.window) as usize;
+        let start = self.buffer.gap_position;
+        for _ in 0..height {
+            let before = self.buffer.gap_position;
+            self.scroll_up();
+            if self.buffer.gap_position == before {
+                break;
+            }
+        }
+        if self.buffer.gap_position == start {
+            // Already on the first line
+            beep();
+            return;
+        }
+        self.put_on_row(row);
+        self.move_cursor_to();
+    }
+
+    pub fn home(&mut self) {
+        // Moves to the first non-blank character of the line, or to its start if already there
+        let left_edge = self.buffer.get_left_edge(self.buffer.gap_position);
+        let right_edge = self.buffer.get_right_edge(self.buffer.gap_position);
+        let mut first_non_blank = left_edge;
+        while first_non_blank < right_edge && matches!(self.buffer.get(first_non_blank), Some(' ') | Some('\t')) {
+            first_non_blank += 1;
+        }
+
+        let new_pos = if self.buffer.gap_position == first_non_blank { left_edge } else { first_non_blank };
+        self.smart_cursor_flag = false;
+        self.move_to(new_pos);
+    }
+
+    pub fn end(&mut self) {
+        // Moves to the end of the line
+        let right_edge = self.buffer.get_right_edge(self.buffer.gap_position);
+        self.smart_cursor_flag = false;
+        self.move_to(right_edge);
+    }
+
+    pub fn buffer_start(&mut self) {
+        self.smart_cursor_flag = false;
+        self.move_to(0);
+    }
+
+    pub fn buffer_end(&mut self) {
+        self.smart_cursor_flag = false;
+        let len = self.buffer.len();
+        self.move_to(len);
+    }
+
     pub fn go_to_line(&mut self, n: usize) {
         // Moves the cursor to the nth line
         if n > self.buffer.n_lines {
@@ -786,6 +987,15 @@ impl GapEditor {
         }
     }
 
+    pub fn select_range(&mut self, lmark: usize, rmark: usize) {
+        // Selects the (inclusive) range [lmark, rmark]
+        self.lmark = lmark;
+        self.rmark = rmark;
+        self.select_mode_flag = true;
+        self.select_active = false;
+        self.select_shift = false;
+    }
+
     pub fn set_select_shift(&mut self) {
         self.select_shift = true;
     }
@@ -801,9 +1011,13 @@ impl GapEditor {
         // Cleans up highlighting
         let region_size = rmark - lmark + 1;
         self.pop_search_regions(lmark, rmark); // Delete any highlighted regions that would be cut
-        self.fix_regions(Adjust::Decrement(region_size));
+        self.search_hits.shift(lmark, -(region_size as isize));
 
         let cut_vector = self.buffer.cut(lmark, rmark, new_cursor_pos);
+        // The characters before the cut are untouched, so the cut started on
+        // the same line as whatever now follows them
+        let line = self.buffer.line_of(min(lmark, self.buffer.len()));
+        self.highlight_edit(line, cut_vector.iter().filter(|c| **c == '\n').count(), 0);
 
         // Cleanup
         self.smart_cursor_flag = false;
@@ -819,9 +1033,14 @@ impl GapEditor {
     }
 
     // Cut/Copy/Paste
-    pub fn cut(&mut self) -> undo::ActionGroup {
+    pub fn cut(&mut self) -> Option<undo::ActionGroup> {
         // Cuts the selected text, or if no text is
         // selected, the current line
+        // Returns None if there is nothing to cut
+
+        if self.buffer.len() == 0 {
+            return None;
+        }
 
         let (lmark, rmark) = self.get_select_region();
 
@@ -849,7 +1068,7 @@ impl GapEditor {
 
         let end_gap_position = self.buffer.gap_position;
 
-        undo::ActionGroup::Singleton(undo::Action::Cut(start_gap_position, lmark, cut_string, end_gap_position))
+        Some(undo::ActionGroup::Singleton(undo::Action::Cut(start_gap_position, lmark, cut_string, end_gap_position)))
     }
 
     pub fn copy(&mut self) {
@@ -866,6 +1085,19 @@ impl GapEditor {
         self.clipboard_cursor = Some(self.clipboard.len() - 1);
     }
 
+    pub fn has_selection(&self) -> bool {
+        self.select_mode_flag
+    }
+
+    pub fn export_selection(&self) -> Option<String> {
+        // Gets the selected text, if any text is selected
+        if !self.select_mode_flag {
+            return None;
+        }
+        let (lmark, rmark) = self.get_select_region();
+        Some(self.buffer.copy(lmark, rmark).into_iter().collect())
+    }
+
     pub fn insert_buffer(&mut self, buffer: &Vec<char>, redo: bool) -> undo::ActionGroup {
         // Inserts buffer at cursor
 
@@ -886,8 +1118,10 @@ impl GapEditor {
 
         let start_gap_position = self.buffer.gap_position;
         let paste_string: String = buffer.to_vec().iter().collect();
+        let line = self.buffer.current_line;
 
         self.buffer.insert_buffer(buffer);
+        self.highlight_edit(line, 0, buffer.iter().filter(|c| **c == '\n').count());
 
         let end_gap_position = self.buffer.gap_position;
 
@@ -946,34 +1180,51 @@ impl GapEditor {
     pub fn find_raw(&self, search_string: String, start: usize) -> Option<Range> {
         // Searches the buffer from `start` and finds
         // the range of first search result
+        // The regex works in byte offsets, whereas the buffer is indexed by char
         let re = match Regex::new(&search_string) {
             Ok(regex) => regex,
             Err(e) => { return None; }
         };
-        match re.find(&self.export()[start..]) {
-            Some(m) => Some((start + m.start(), start + m.end())),
-            None => None
+        let text = self.export();
+        re.find_at(&text, search::char_to_byte(&text, start))
+            .map(|m| search::char_range(&syntax_highlighting::generate_offsets(&text), m))
+    }
+
+    pub fn find_all(&mut self, query: &search::SearchQuery) {
+        // Searches the buffer (or the query's scope) and finds
+        // every range and adds it to search_hits, moving
+        // the cursor to the first one from the cursor on
+        if query.scope.is_some() {
+            // The hits would be hidden under the selection
+            self.deselect_marks();
+        }
+        if self.search_all(query).is_ok() {
+            self.goto_search_hit_from(self.buffer.gap_position);
         }
     }
 
-    pub fn find_all(&mut self, search_string: String, start: usize) {
-        // Searches the buffer from `start` and finds
-        // every range and adds it to search_hits
-        let re = match Regex::new(&search_string) {
-            Ok(regex) => regex,
-            Err(e) => { return; }
-        };
-        let mut flag = false;
-        for m in re.find_iter(&self.export()[start..]) {
-            if !flag {
-                let s = start + m.start();
-                self.buffer.move_gap(s);
-                self.move_cursor_to();
-                flag = true;
-            }
-            let range = (start + m.start(), start + m.end());
-            self.search_hits.push(range);
+    pub fn search_all(&mut self, query: &search::SearchQuery) -> Result<(), regex::Error> {
+        // Same as find_all, but leaves the cursor where it is
+        let re = query.compile()?;
+        let text = self.export();
+        for (l, r) in search::find_hits(&re, &text, query.scope) {
+            self.search_hits.insert(l, r, ());
         }
+        Ok(())
+    }
+
+    pub fn goto_search_hit_from(&mut self, pos: usize) -> bool {
+        // Moves the cursor to the first search hit at or after pos, wrapping
+        // around to the first hit in the buffer; returns false if there are none
+        let hit = match self.search_hits.first_from(pos) {
+            Some(hit) => hit,
+            None => match self.search_hits.first() {
+                Some(hit) => hit,
+                None => { return false; }
+            }
+        };
+        self.jump_to_search_hit(hit.0, false);
+        true
     }
 
     pub fn find(&mut self, search_string: String) {
@@ -982,7 +1233,7 @@ impl GapEditor {
         match self.find_raw(search_string, self.buffer.gap_position) {
             Some((start, end)) => {
                 self.buffer.move_gap(start);
-                self.search_hits.push((start, end));
+                self.search_hits.insert(start, end, ());
                 self.move_cursor_to();
             },
             None => {}
@@ -995,21 +1246,32 @@ impl GapEditor {
 
         let (range_l, range_r) = range;
         assert!(range_l <= range_r);
-        assert!(range_r < self.buffer.len());
+        assert!(range_r <= self.buffer.len());
 
         // This is the new cursor position after cutting AND pasting
+        let replace_len = replace_with.chars().count();
         let new_cursor_pos = if self.buffer.gap_position < range_l { // Before the replace region -> do nothing
             self.buffer.gap_position
         } else if self.buffer.gap_position >= range_r { // After the replace region -> Add the difference in lengths
-            self.buffer.gap_position + replace_with.len() - (range_r - range_l)
+            self.buffer.gap_position + replace_len - (range_r - range_l)
         } else { // Cursor between the replace regions -> move cursor to the end of the replaced string
-            range_l + replace_with.len()
+            range_l + replace_len
         };
 
         // Move the cursor to range_l after so that we don't have to move the cursor when pasting
-        let buffer = self.buffer.cut(range_l, range_r - 1, range_l); // Dijkstra to inclusive range
+        let line = self.buffer.line_of(range_l);
+        let buffer = if range_r > range_l {
+            self.pop_search_regions(range_l, range_r - 1);
+            self.buffer.cut(range_l, range_r - 1, range_l) // Dijkstra to inclusive range
+        } else { // Empty range (e.g. a regex hit on ^) -> nothing to cut, only insert
+            self.buffer.move_gap(range_l);
+            Vec::new()
+        };
+        self.search_hits.shift(range_l, range_l as isize - range_r as isize);
+        self.search_hits.shift(range_l, replace_len as isize);
         let replaced_string = buffer.iter().collect();
         self.buffer.insert_buffer(&replace_with.chars().collect());
+        self.highlight_edit(line, buffer.iter().filter(|c| **c == '\n').count(), replace_with.matches('\n').count());
         self.buffer.move_gap(new_cursor_pos);
         self.move_cursor_to();
 
@@ -1025,24 +1287,32 @@ impl GapEditor {
         undo::ActionGroup::Singleton(undo::Action::Replace(range_l, replaced_string, replace_with.clone()))        
     }
 
-    pub fn replace_all(&mut self, replace_with: String) -> Option<undo::ActionGroup> {
+    pub fn replace_all(&mut self, query: &search::SearchQuery, replace_with: String) -> Option<undo::ActionGroup> {
         // Replace all regions in the search_hits vector
-        // with the given string
+        // with the given string (expanding capture groups
+        // for regex queries)
 
-        // Clone search_hits so we don't run into mut borrow issues
-        let search_hits = self.search_hits.clone();
+        // Copy the hits out so we don't run into mut borrow issues
+        let search_hits: Vec<Range> = self.search_hits.intervals().into_iter().map(|(range, _)| range).collect();
         if search_hits.len() == 0 {
             return None;
         }
+        let re = match query.compile() {
+            Ok(regex) => regex,
+            Err(_) => { return None; }
+        };
+
+        // Work out every replacement before the text starts changing
+        let text = self.export();
+        let replacements = query.replacements(&re, &text, &search_hits, &replace_with);
 
-        let mut pos_diff: i32 = 0; // Use this to adjust the difference in position
         let mut action_groups = Vec::<undo::ActionGroup>::new();
-        for (l, r) in search_hits {
-            let adj_l = (l as i32 + pos_diff) as usize;
-            let adj_r = (r as i32 + pos_diff) as usize;
-            self.buffer.move_gap(adj_r); // This makes moving the cursor to the end easier
-            action_groups.push(self.replace((adj_l, adj_r), replace_with.clone(), false));
-            pos_diff += replace_with.len() as i32 - (r - l) as i32;
+        let mut replaced_ranges = Vec::<Range>::new();
+        for ((l, r), replacement) in replacements {
+            let replacement_len = replacement.chars().count();
+            self.buffer.move_gap(r); // This makes moving the cursor to the end easier
+            action_groups.push(self.replace((l, r), replacement, false));
+            replaced_ranges.push((l, l + replacement_len));
 
             // Debug
             /*
@@ -1056,11 +1326,10 @@ impl GapEditor {
         // Delete search highlights
         self.clear_search();
 
-        // After replacing, "search" for the replace term
-        let cpos = self.buffer.gap_position; // Store the cursor position, as find_all is movey
-        self.find_all(replace_with, 0);
-        self.buffer.move_gap(cpos); // Move the cursor back
-        //TODO Move find_all logic to a separate non-movey method and call that instead
+        // After replacing, highlight the replacements
+        for (l, r) in replaced_ranges {
+            self.search_hits.insert(l, r, ());
+        }
         self.move_cursor_to();
 
         // Cleanup
@@ -1071,20 +1340,81 @@ impl GapEditor {
         Some(undo::merge_action_groups(action_groups))
     }
 
+    pub fn replace_search_hit(&mut self, query: &search::SearchQuery, range: Range, replace_with: &str) -> Option<undo::ActionGroup> {
+        // Replaces a single search hit (expanding capture groups for regex
+        // queries), leaving the cursor after the replacement
+        let re = match query.compile() {
+            Ok(regex) => regex,
+            Err(_) => { return None; }
+        };
+        let text = self.export();
+        let replacement = query.expand(&re, &text, search::char_to_byte(&text, range.0), replace_with);
+        self.buffer.move_gap(range.1);
+        Some(self.replace(range, replacement, false))
+    }
+
+    pub fn pop_search_hit_from(&mut self, pos: usize) -> Option<Range> {
+        // Removes the first search hit at or after pos, wrapping around to the
+        // first hit in the buffer, and moves the cursor to it
+        let (range, handle) = match self.search_hits.first_from(pos) {
+            Some(hit) => hit,
+            None => self.search_hits.first()?
+        };
+        self.search_hits.delete(handle);
+        self.buffer.move_gap(range.0);
+        self.move_cursor_to();
+        Some(range)
+    }
+
     pub fn clear_search(&mut self) {
-        // Clears the search_hits vector
+        // Clears the search hits
         self.search_hits.clear();
     }
 
+    pub fn has_search_hits(&self) -> bool {
+        !self.search_hits.is_empty()
+    }
+
+    pub fn next_search_hit(&mut self) -> Option<(usize, usize, bool)> {
+        // Moves the cursor to the first search hit after it, wrapping around
+        // to the first hit in the buffer. Returns (k, n, wrapped) for "match k of n"
+        let (hit, wrapped) = match self.search_hits.next_after(self.buffer.gap_position) {
+            Some(hit) => (hit, false),
+            None => (self.search_hits.first()?, true)
+        };
+        Some(self.jump_to_search_hit(hit.0, wrapped))
+    }
+
+    pub fn prev_search_hit(&mut self) -> Option<(usize, usize, bool)> {
+        // Moves the cursor to the last search hit before it, wrapping around
+        // to the last hit in the buffer. Returns (k, n, wrapped) for "match k of n"
+        let (hit, wrapped) = match self.search_hits.prev_before(self.buffer.gap_position) {
+            Some(hit) => (hit, false),
+            None => (self.search_hits.last()?, true)
+        };
+        Some(self.jump_to_search_hit(hit.0, wrapped))
+    }
+
+    pub fn current_search_hit(&self) -> Option<(usize, usize)> {
+        // Gets (k, n) if the cursor is at the start of the kth of n search hits
+        let k = self.search_hits.count_before(self.buffer.gap_position);
+        if self.search_hits.count_before(self.buffer.gap_position + 1) > k {
+            Some((k + 1, self.search_hits.len()))
+        } else {
+            None
+        }
+    }
+
+    fn jump_to_search_hit(&mut self, range: Range, wrapped: bool) -> (usize, usize, bool) {
+        self.buffer.move_gap(range.0);
+        self.move_cursor_to();
+        (self.search_hits.count_before(range.0) + 1, self.search_hits.len(), wrapped)
+    }
+
     pub fn index_in_search_hits(&self, index: usize) -> bool {
         // Checks if index is in a search_hits
         // range
-        for (a, b) in self.search_hits.iter() {
-            if *a <= index && index < *b {
-                return true;
-            }
-        }
-        false
+        !self.search_hits.query_point(index).is_empty()
     }
 
     // Misc helper functions (aides functions above)
@@ -1107,16 +1437,7 @@ impl GapEditor {
                 }
 
                 // Handle highlighted regions
-                for i in 0..self.search_hits.len() {
-                    let (mut lmark, mut rmark) = self.search_hits[i];
-                    if self.buffer.gap_position <= lmark {
-                        lmark += amount;
-                    }
-                    if self.buffer.gap_position <= rmark {
-                        rmark += amount;
-                    }
-                    self.search_hits[i] = (lmark, rmark);
-                }
+                self.search_hits.shift(self.buffer.gap_position, amount as isize);
             },
             Adjust::Decrement(amount) => {
                 // Handle selection
@@ -1131,16 +1452,9 @@ impl GapEditor {
                     }
                 }
 
-                // Handle highlighted regions
-                for i in 0..self.search_hits.len() {
-                    let (mut lmark, mut rmark) = self.search_hits[i];
-                    if self.buffer.gap_position <= lmark {
-                        lmark -= amount;
-                    }
-                    if self.buffer.gap_position <= rmark {
-                        rmark -= amount;
-                    }
-                    self.search_hits[i] = (lmark, rmark);
+                // Handle highlighted regions (the deleted text is right before the cursor)
+                if let Some(offset) = self.buffer.gap_position.checked_sub(amount) {
+                    self.search_hits.shift(offset, -(amount as isize));
                 }
             }
         }
@@ -1148,7 +1462,11 @@ impl GapEditor {
 
     pub fn pop_search_regions(&mut self, lmark: usize, rmark: usize) {
         // Removes all search results with bounds within [lmark, rmark]
-        self.search_hits = self.search_hits.iter().filter(|(l, r)| *l < lmark || *r > rmark).cloned().collect();
+        for ((l, r), handle) in self.search_hits.query_range(lmark, rmark + 1) {
+            if lmark <= l && r <= rmark {
+                self.search_hits.delete(handle);
+            }
+        }
     }
 
     // History
@@ -1184,13 +1502,17 @@ impl GapEditor {
                 self.backspace(redo);
                 self.buffer.move_gap(end);
             },
+            undo::Action::DeleteForward(start, _) => {
+                self.buffer.move_gap(start);
+                self.delete(redo);
+            },
             undo::Action::Replace(range_l, replaced, replacing) => {
-                let range_r = range_l + replaced.len();
+                let range_r = range_l + replaced.chars().count();
                 self.replace((range_l, range_r), replacing.clone(), redo);
                 self.buffer.move_gap(range_l);
             },
             undo::Action::Cut(start, range_l, cut_string, end) => {
-                let range_r = range_l + cut_string.len() - 1;
+                let range_r = range_l + cut_string.chars().count() - 1;
                 self.cut_raw(range_l, range_r, range_l, redo);
                 self.buffer.move_gap(end);
             },
@@ -1270,28 +1592,29 @@ impl GapEditor {
         self.redo_history.clear();
     }
 
-    // Handles Regex Tree Recompilation
-    pub fn recompile_regex_tree(&mut self) {
-        self.recompile_regex_tree_flag = true;
+    // Keeps the syntax highlighting in sync with edits
+    fn highlight_edit(&mut self, line: usize, removed_lines: usize, inserted_lines: usize) {
+        // Tells the highlighter that an edit starting on `line` removed and
+        // inserted the given number of newlines
+        if let Some(ref mut highlighter) = self.highlighter {
+            highlighter.edit(line, removed_lines, inserted_lines);
+        }
     }
 
     // Actions that modify history
     pub fn type_character_h(&mut self, ch: char) {
         let ag = self.type_character(ch, false);
         self.push_history(ag);
-        self.recompile_regex_tree();
     }
 
     pub fn newline_h(&mut self) {
         let ag = self.newline(false);
         self.push_history(ag);
-        self.recompile_regex_tree();
     }
 
     pub fn tab_h(&mut self) {
         let ag = self.tab(false);
         self.push_history(ag);
-        self.recompile_regex_tree();
     }
 
     pub fn backspace_h(&mut self) {
@@ -1300,13 +1623,21 @@ impl GapEditor {
             Some(ag) => { self.push_history(ag); },
             None => {}
         };
-        self.recompile_regex_tree();
+    }
+
+    pub fn delete_h(&mut self) {
+        if let Some(ag) = self.delete(false) {
+            self.push_history(ag);
+        }
     }
 
     pub fn cut_h(&mut self) {
-        let ag = self.cut();
-        self.push_history(ag);
-        self.recompile_regex_tree();
+        match self.cut() {
+            Some(ag) => {
+                self.push_history(ag);
+            },
+            None => { beep(); }
+        };
     }
 
     pub fn paste_h(&mut self) {
@@ -1314,7 +1645,6 @@ impl GapEditor {
         match maybe_ag {
             Some(ag) => {
                 self.push_history(ag);
-                self.recompile_regex_tree();
             },
             None => { beep(); }
         };
@@ -1323,15 +1653,13 @@ impl GapEditor {
     pub fn replace_h(&mut self, range: (usize, usize), replace_with: String) {
         let ag = self.replace(range, replace_with, false);
         self.push_history(ag);
-        self.recompile_regex_tree();
     }
 
-    pub fn replace_all_h(&mut self, replace_with: String) {
-        let maybe_ag = self.replace_all(replace_with);
+    pub fn replace_all_h(&mut self, query: &search::SearchQuery, replace_with: String) {
+        let maybe_ag = self.replace_all(query, replace_with);
         match maybe_ag {
             Some(ag) => {
                 self.push_history(ag);
-                self.recompile_regex_tree();
             },
             None => {}
         };
@@ -1380,13 +1708,13 @@ fn cursor_bottom(window: WINDOW, height: usize) -> bool {
     cur_y == (height - 1) as i32
 }
 
-fn cursor_beginning(window: WINDOW) -> bool {
-    // Returns whether the cursor is in the top left corner or not
+fn cursor_beginning(window: WINDOW, left: usize) -> bool {
+    // Returns whether the cursor is in the top left corner (of the text, right of the gutter) or not
     let mut cur_x = 0;
     let mut cur_y = 0;
 
     getyx(window, &mut cur_y, &mut cur_x);
-    cur_y == 0 && cur_x == 0
+    cur_y == 0 && cur_x == left as i32
 }
 
 fn cursor_end(window: WINDOW, height: usize, width: usize) -> bool {
@@ -1398,26 +1726,36 @@ fn cursor_end(window: WINDOW, height: usize, width: usize) -> bool {
     cur_y == (height - 1) as i32 && cur_x == (width - 1) as i32
 }
 
-fn waddch_with_highlight(window: WINDOW, ch: chtype) {
+fn wadd_char(window: WINDOW, ch: char) {
+    // Adds a character; anything outside ASCII goes through waddstr, so that
+    // ncurses encodes it for the locale (waddch can only take single bytes)
+    if ch.is_ascii() {
+        waddch(window, ch as chtype);
+    } else {
+        waddstr(window, ch.encode_utf8(&mut [0; 4])).unwrap();
+    }
+}
+
+fn waddch_with_highlight(window: WINDOW, ch: char) {
     // Add character with background highlighting
     //wattron(window, COLOR_PAIR(1)); // CP_HIGHLIGHT
     wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    waddch(window, ch);
+    wadd_char(window, ch);
     //wattroff(window, COLOR_PAIR(1));
     wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
 }
 
-fn waddch_with_search(window: WINDOW, ch: chtype) {
+fn waddch_with_search(window: WINDOW, ch: char) {
     // Add character with background search highlighting
     wattron(window, COLOR_PAIR(colors::CP_SEARCH));
-    waddch(window, ch);
+    wadd_char(window, ch);
     wattroff(window, COLOR_PAIR(colors::CP_SEARCH));
 }
 
-fn waddch_with_color(window: WINDOW, ch: chtype, color: u64) {
+fn waddch_with_color(window: WINDOW, ch: char, color: u64) {
     // Add character with user-provided color
     wattron(window, color);
-    waddch(window, ch);
+    wadd_char(window, ch);
     wattroff(window, color);
 }
 
@@ -1436,3 +1774,39 @@ fn debug_wait_for_input(window: WINDOW) {
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> GapEditor {
+        // ncurses ignores calls on a null window, so the editor only needs a size to work without a screen
+        let mut editor = GapEditor::from_string(text, std::ptr::null_mut());
+        editor.size = (24, 80);
+        editor
+    }
+
+    #[test]
+    fn replace_empty_hits() {
+        let mut editor = editor("one\ntwo\nthree");
+        let query = search::SearchQuery::new("^".to_string(), true);
+        editor.search_all(&query).unwrap();
+        editor.replace_all_h(&query, "# ".to_string());
+        assert_eq!(editor.export(), "# one\n# two\n# three");
+        editor.undo();
+        assert_eq!(editor.export(), "one\ntwo\nthree");
+        editor.redo();
+        assert_eq!(editor.export(), "# one\n# two\n# three");
+    }
+
+    #[test]
+    fn undo_replace_with_nothing() {
+        let mut editor = editor("foo bar baz");
+        editor.replace_h((4, 8), String::new());
+        assert_eq!(editor.export(), "foo baz");
+        editor.undo();
+        assert_eq!(editor.export(), "foo bar baz");
+        editor.redo();
+        assert_eq!(editor.export(), "foo baz");
+    }
+}
@@ -1,35 +1,535 @@
-extern crate petgraph;
-use petgraph::{Graph, Directed};
-use petgraph::graph::{EdgeIndex, NodeIndex};
+extern crate generational_arena;
+use generational_arena::{Arena, Index};
+use std::cmp::max;
+
+type Range = (usize, usize); // Dijkstra range: [a, b)
+
+// Augmented interval tree, implemented as a treap ordered by interval start
+// Each node keeps the largest interval end in its subtree, so that queries can
+// skip subtrees that end too early, and a pending shift for its whole subtree,
+// so that moving every interval after an edit doesn't have to visit them all
 
 pub struct Interval<T> {
     lrange: usize,
     rrange: usize,
-    max: usize,
+    max: usize, // Largest rrange in the subtree
     val: T
 }
 
+struct Node<T> {
+    interval: Interval<T>,
+    shift: isize, // Not yet applied to this node's subtree (including the node itself)
+    size: usize, // Number of intervals in the subtree
+    priority: u64,
+    left: Option<Index>,
+    right: Option<Index>,
+    parent: Option<Index>
+}
+
 pub struct IntervalTree<T> {
-    graph: Graph<Interval<T>, bool, Directed>,
-    graph_head: Option<NodeIndex<u32>>
+    arena: Arena<Node<T>>,
+    root: Option<Index>,
+    seed: u64 // State of the node priority generator
+}
+
+impl<T> Default for IntervalTree<T> {
+    fn default() -> IntervalTree<T> {
+        IntervalTree::new()
+    }
 }
 
 impl<T> IntervalTree<T> {
     pub fn new() -> IntervalTree<T> {
-        let graph = Graph::<Interval<T>, bool, Directed>::new();
-        IntervalTree { graph: graph, graph_head: None }
+        IntervalTree { arena: Arena::new(), root: None, seed: 0x2545f4914f6cdd1d }
     }
 
-    pub fn insert(&mut self, interval: Interval<T>) {
-        // Inserts an interval
-        match self.graph_head {
-            None => {
-                let head = self.graph.add_node(interval);
-                self.graph_head = Some(head);
+    pub fn len(&self) -> usize {
+        self.arena.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arena.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.arena.clear();
+        self.root = None;
+    }
+
+    pub fn insert(&mut self, lrange: usize, rrange: usize, val: T) -> Index {
+        // Inserts the interval [lrange, rrange), returning a handle to it
+        assert!(lrange <= rrange);
+        let priority = self.next_priority();
+        let interval = Interval { lrange, rrange, max: rrange, val };
+        let node = self.arena.insert(Node { interval, shift: 0, size: 1, priority, left: None, right: None, parent: None });
+
+        let (left, right) = self.split(self.root, lrange);
+        let left = self.merge(left, Some(node));
+        self.root = self.merge(left, right);
+        self.fix_root();
+        node
+    }
+
+    pub fn delete(&mut self, handle: Index) -> Option<(Range, T)> {
+        // Removes an interval, returning its bounds and value
+        if !self.arena.contains(handle) {
+            return None;
+        }
+        let path = self.path_to(handle);
+        for node in path.iter() {
+            self.push(*node);
+        }
+
+        let (left, right, parent) = {
+            let node = &self.arena[handle];
+            (node.left, node.right, node.parent)
+        };
+        let merged = self.merge(left, right);
+        match parent {
+            Some(parent) => {
+                if self.arena[parent].left == Some(handle) {
+                    self.arena[parent].left = merged;
+                } else {
+                    self.arena[parent].right = merged;
+                }
+                for node in path.iter().rev().skip(1) {
+                    self.update(*node);
+                }
             },
-            Some(head) => {
-                let head_interval = self.graph.node_weight(head).unwrap();
+            None => {
+                self.root = merged;
+                self.fix_root();
+            }
+        }
+
+        let node = self.arena.remove(handle).unwrap();
+        Some(((node.interval.lrange, node.interval.rrange), node.interval.val))
+    }
+
+    pub fn query_point(&self, point: usize) -> Vec<(Range, Index)> {
+        // Gets the intervals containing point, ordered by start
+        self.query_range(point, point + 1)
+    }
+
+    pub fn query_range(&self, lrange: usize, rrange: usize) -> Vec<(Range, Index)> {
+        // Gets the intervals overlapping [lrange, rrange), ordered by start
+        let mut out = Vec::new();
+        self.collect(self.root, 0, &mut out, &|a, b| a < rrange && lrange < b, &|a| a >= rrange, &|max| max <= lrange);
+        out
+    }
+
+    pub fn intervals(&self) -> Vec<(Range, Index)> {
+        // Gets every interval, ordered by start
+        let mut out = Vec::new();
+        self.collect(self.root, 0, &mut out, &|_, _| true, &|_| false, &|_| false);
+        out
+    }
+
+    pub fn first(&self) -> Option<(Range, Index)> {
+        // Gets the interval that starts first
+        self.find_nearest(0, true)
+    }
+
+    pub fn last(&self) -> Option<(Range, Index)> {
+        // Gets the interval that starts last
+        self.find_nearest(usize::MAX, false)
+    }
+
+    pub fn first_from(&self, point: usize) -> Option<(Range, Index)> {
+        // Gets the first interval starting at or after point
+        self.find_nearest(point, true)
+    }
+
+    pub fn next_after(&self, point: usize) -> Option<(Range, Index)> {
+        // Gets the first interval starting after point
+        self.find_nearest(point + 1, true)
+    }
+
+    pub fn prev_before(&self, point: usize) -> Option<(Range, Index)> {
+        // Gets the last interval starting before point
+        self.find_nearest(point, false)
+    }
+
+    pub fn count_before(&self, point: usize) -> usize {
+        // Counts the intervals starting before point
+        let mut count = 0;
+        let mut node = self.root;
+        let mut shift = 0;
+        while let Some(index) = node {
+            let current = &self.arena[index];
+            shift += current.shift;
+            if shifted(current.interval.lrange, shift) < point {
+                count += 1 + current.left.map_or(0, |left| self.arena[left].size);
+                node = current.right;
+            } else {
+                node = current.left;
+            }
+        }
+        count
+    }
+
+    pub fn shift(&mut self, offset: usize, delta: isize) {
+        // Adjusts the intervals after an edit at offset that inserted delta
+        // characters (or deleted -delta characters, if negative): every
+        // endpoint at or after offset moves by delta, and endpoints inside
+        // deleted text end up at offset. The intervals that start after the
+        // edit are shifted lazily; only those that overlap it are visited
+        let removed = if delta < 0 { delta.unsigned_abs() } else { 0 };
+        let (before, rest) = self.split(self.root, offset);
+        let (inside, after) = self.split(rest, offset + removed);
+        self.shift_ends(before, offset, delta);
+        self.shift_ends(inside, offset, delta);
+        if let Some(after) = after {
+            self.arena[after].shift += delta;
+        }
+
+        let left = self.merge(before, inside);
+        self.root = self.merge(left, after);
+        self.fix_root();
+    }
+
+    // Helper functions
+
+    fn collect(&self, node: Option<Index>, shift: isize, out: &mut Vec<(Range, Index)>,
+               overlaps: &dyn Fn(usize, usize) -> bool, starts_after: &dyn Fn(usize) -> bool, ends_before: &dyn Fn(usize) -> bool) {
+        // In-order walk collecting the intervals for which overlaps holds.
+        // Stops going right once an interval starts_after the query, and skips
+        // subtrees whose largest end is before it
+        let index = match node {
+            Some(index) => index,
+            None => { return; }
+        };
+        let node = &self.arena[index];
+        let shift = shift + node.shift;
+        if ends_before(shifted(node.interval.max, shift)) {
+            return;
+        }
+
+        self.collect(node.left, shift, out, overlaps, starts_after, ends_before);
+        let (lrange, rrange) = (shifted(node.interval.lrange, shift), shifted(node.interval.rrange, shift));
+        if starts_after(lrange) {
+            return;
+        }
+        if overlaps(lrange, rrange) {
+            out.push(((lrange, rrange), index));
+        }
+        self.collect(node.right, shift, out, overlaps, starts_after, ends_before);
+    }
+
+    fn find_nearest(&self, key: usize, want_after: bool) -> Option<(Range, Index)> {
+        // Gets the first interval starting at or after key if want_after,
+        // or else the last interval starting before it
+        let mut before = None;
+        let mut after = None;
+        let mut node = self.root;
+        let mut shift = 0;
+        while let Some(index) = node {
+            let current = &self.arena[index];
+            shift += current.shift;
+            let range = (shifted(current.interval.lrange, shift), shifted(current.interval.rrange, shift));
+            if range.0 >= key {
+                after = Some((range, index));
+                node = current.left;
+            } else {
+                before = Some((range, index));
+                node = current.right;
             }
         }
+        if want_after { after } else { before }
+    }
+
+    fn shift_ends(&mut self, node: Option<Index>, offset: usize, delta: isize) {
+        // Moves the endpoints at or after offset in a subtree (see shift)
+        let node = match node {
+            Some(node) => node,
+            None => { return; }
+        };
+        self.push(node);
+        if self.arena[node].interval.max < offset {
+            return;
+        }
+        let fix = |point: usize| if point >= offset { max(point as isize + delta, offset as isize) as usize } else { point };
+        let interval = &mut self.arena[node].interval;
+        interval.lrange = fix(interval.lrange);
+        interval.rrange = fix(interval.rrange);
+
+        let (left, right) = (self.arena[node].left, self.arena[node].right);
+        self.shift_ends(left, offset, delta);
+        self.shift_ends(right, offset, delta);
+        self.update(node);
+    }
+
+    fn split(&mut self, node: Option<Index>, key: usize) -> (Option<Index>, Option<Index>) {
+        // Splits a subtree into the intervals starting before key and the rest
+        let node = match node {
+            Some(node) => node,
+            None => { return (None, None); }
+        };
+        self.push(node);
+        if self.arena[node].interval.lrange < key {
+            let (left, right) = self.split(self.arena[node].right, key);
+            self.arena[node].right = left;
+            self.update(node);
+            (Some(node), right)
+        } else {
+            let (left, right) = self.split(self.arena[node].left, key);
+            self.arena[node].left = right;
+            self.update(node);
+            (left, Some(node))
+        }
+    }
+
+    fn merge(&mut self, left: Option<Index>, right: Option<Index>) -> Option<Index> {
+        // Joins two subtrees, where every interval in left starts no later than those in right
+        let (left, right) = match (left, right) {
+            (None, other) | (other, None) => { return other; },
+            (Some(left), Some(right)) => (left, right)
+        };
+        if self.arena[left].priority > self.arena[right].priority {
+            self.push(left);
+            let merged = self.merge(self.arena[left].right, Some(right));
+            self.arena[left].right = merged;
+            self.update(left);
+            Some(left)
+        } else {
+            self.push(right);
+            let merged = self.merge(Some(left), self.arena[right].left);
+            self.arena[right].left = merged;
+            self.update(right);
+            Some(right)
+        }
+    }
+
+    fn push(&mut self, node: Index) {
+        // Applies a node's pending shift to itself and hands it down to its children
+        let shift = self.arena[node].shift;
+        if shift == 0 {
+            return;
+        }
+        let (left, right) = {
+            let node = &mut self.arena[node];
+            node.shift = 0;
+            node.interval.lrange = shifted(node.interval.lrange, shift);
+            node.interval.rrange = shifted(node.interval.rrange, shift);
+            node.interval.max = shifted(node.interval.max, shift);
+            (node.left, node.right)
+        };
+        for child in [left, right].into_iter().flatten() {
+            self.arena[child].shift += shift;
+        }
+    }
+
+    fn update(&mut self, node: Index) {
+        // Recomputes a (pushed) node's max and size after its children
+        // changed, and points the children back at it
+        let (left, right) = (self.arena[node].left, self.arena[node].right);
+        let mut max_end = self.arena[node].interval.rrange;
+        let mut size = 1;
+        for child in [left, right].into_iter().flatten() {
+            let child_node = &mut self.arena[child];
+            child_node.parent = Some(node);
+            max_end = max(max_end, shifted(child_node.interval.max, child_node.shift));
+            size += child_node.size;
+        }
+        self.arena[node].interval.max = max_end;
+        self.arena[node].size = size;
+    }
+
+    fn fix_root(&mut self) {
+        if let Some(root) = self.root {
+            self.arena[root].parent = None;
+        }
+    }
+
+    fn path_to(&self, node: Index) -> Vec<Index> {
+        // Gets the nodes from the root down to node
+        let mut path = vec![node];
+        while let Some(parent) = self.arena[*path.last().unwrap()].parent {
+            path.push(parent);
+        }
+        path.reverse();
+        path
+    }
+
+    fn next_priority(&mut self) -> u64 {
+        // xorshift64
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed = x;
+        x
+    }
+}
+
+fn shifted(point: usize, shift: isize) -> usize {
+    (point as isize + shift) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges<T>(tree: &IntervalTree<T>) -> Vec<Range> {
+        tree.intervals().into_iter().map(|(range, _)| range).collect()
+    }
+
+    fn tree_of(intervals: &[Range]) -> IntervalTree<()> {
+        let mut tree = IntervalTree::new();
+        for (l, r) in intervals {
+            tree.insert(*l, *r, ());
+        }
+        tree
+    }
+
+    #[test]
+    fn insert_keeps_intervals_ordered() {
+        let tree = tree_of(&[(10, 12), (0, 3), (5, 9), (5, 6)]);
+        assert_eq!(tree.len(), 4);
+        let starts: Vec<usize> = ranges(&tree).iter().map(|range| range.0).collect();
+        assert_eq!(starts, vec![0, 5, 5, 10]);
+    }
+
+    #[test]
+    fn delete_returns_bounds_and_value() {
+        let mut tree = IntervalTree::new();
+        tree.insert(0, 3, 'a');
+        let handle = tree.insert(5, 9, 'b');
+        tree.insert(10, 12, 'c');
+        assert_eq!(tree.delete(handle), Some(((5, 9), 'b')));
+        assert_eq!(tree.delete(handle), None);
+        assert_eq!(ranges(&tree), vec![(0, 3), (10, 12)]);
+    }
+
+    #[test]
+    fn delete_everything() {
+        let mut tree = tree_of(&[(0, 3), (5, 9), (10, 12)]);
+        while let Some((_, handle)) = tree.first() {
+            tree.delete(handle);
+        }
+        assert!(tree.is_empty());
+        assert_eq!(tree.first(), None);
+    }
+
+    #[test]
+    fn query_point() {
+        let tree = tree_of(&[(0, 5), (3, 8), (8, 10)]);
+        let hits = |point| tree.query_point(point).into_iter().map(|(range, _)| range).collect::<Vec<Range>>();
+        assert_eq!(hits(0), vec![(0, 5)]);
+        assert_eq!(hits(4), vec![(0, 5), (3, 8)]);
+        assert_eq!(hits(8), vec![(8, 10)]); // Ends are exclusive
+        assert_eq!(hits(10), vec![]);
+    }
+
+    #[test]
+    fn query_range() {
+        let tree = tree_of(&[(0, 2), (4, 6), (6, 9), (20, 30)]);
+        let hits = |l, r| tree.query_range(l, r).into_iter().map(|(range, _)| range).collect::<Vec<Range>>();
+        assert_eq!(hits(2, 4), vec![]);
+        assert_eq!(hits(1, 5), vec![(0, 2), (4, 6)]);
+        assert_eq!(hits(5, 25), vec![(4, 6), (6, 9), (20, 30)]);
+    }
+
+    #[test]
+    fn first_and_last() {
+        let tree = tree_of(&[(7, 8), (2, 4), (12, 13)]);
+        assert_eq!(tree.first().map(|hit| hit.0), Some((2, 4)));
+        assert_eq!(tree.last().map(|hit| hit.0), Some((12, 13)));
+    }
+
+    #[test]
+    fn next_after_and_prev_before() {
+        let tree = tree_of(&[(2, 4), (7, 8), (12, 13)]);
+        assert_eq!(tree.next_after(2).map(|hit| hit.0), Some((7, 8)));
+        assert_eq!(tree.next_after(6).map(|hit| hit.0), Some((7, 8)));
+        assert_eq!(tree.next_after(12), None);
+        assert_eq!(tree.first_from(7).map(|hit| hit.0), Some((7, 8)));
+        assert_eq!(tree.prev_before(7).map(|hit| hit.0), Some((2, 4)));
+        assert_eq!(tree.prev_before(8).map(|hit| hit.0), Some((7, 8)));
+        assert_eq!(tree.prev_before(2), None);
+    }
+
+    #[test]
+    fn count_before() {
+        let tree = tree_of(&[(2, 4), (7, 8), (7, 9), (12, 13)]);
+        assert_eq!(tree.count_before(0), 0);
+        assert_eq!(tree.count_before(7), 1);
+        assert_eq!(tree.count_before(8), 3);
+        assert_eq!(tree.count_before(100), 4);
+    }
+
+    #[test]
+    fn shift_after_insert() {
+        // Three characters typed at 5
+        let mut tree = tree_of(&[(0, 2), (3, 7), (5, 6), (10, 12)]);
+        tree.shift(5, 3);
+        assert_eq!(ranges(&tree), vec![(0, 2), (3, 10), (8, 9), (13, 15)]);
+        assert_eq!(tree.query_point(14).len(), 1);
+        assert_eq!(tree.count_before(8), 2);
+    }
+
+    #[test]
+    fn delete_swallowing_an_interval() {
+        // [3, 13) deleted
+        let mut tree = tree_of(&[(0, 2), (5, 8), (20, 22)]);
+        tree.shift(3, -10);
+        assert_eq!(ranges(&tree), vec![(0, 2), (3, 3), (10, 12)]);
+    }
+
+    #[test]
+    fn delete_straddling_an_interval() {
+        // [7, 12) deleted
+        let mut tree = tree_of(&[(5, 10), (10, 20), (30, 31)]);
+        tree.shift(7, -5);
+        assert_eq!(ranges(&tree), vec![(5, 7), (7, 15), (25, 26)]);
+    }
+
+    #[test]
+    fn matches_a_plain_list() {
+        // Random inserts, deletes and edits, checked against a sorted list
+        let mut tree = IntervalTree::new();
+        let mut list: Vec<(Range, Index)> = Vec::new();
+        let mut seed: u64 = 1;
+        let mut random = |n: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((seed >> 33) % n) as usize
+        };
+        for _ in 0..500 {
+            match random(4) {
+                0 | 1 => {
+                    let l = random(200);
+                    let r = l + random(10);
+                    list.push(((l, r), tree.insert(l, r, ())));
+                },
+                2 if !list.is_empty() => {
+                    let (range, handle) = list.remove(random(list.len() as u64));
+                    assert_eq!(tree.delete(handle).map(|hit| hit.0), Some(range));
+                },
+                _ => {
+                    let offset = random(200);
+                    let delta = random(20) as isize - 10;
+                    tree.shift(offset, delta);
+                    let removed = if delta < 0 { delta.unsigned_abs() } else { 0 };
+                    let fix = |point: usize| match point {
+                        p if p >= offset + removed => shifted(p, delta),
+                        p if p >= offset => offset,
+                        p => p
+                    };
+                    for (range, _) in list.iter_mut() {
+                        *range = (fix(range.0), fix(range.1));
+                    }
+                }
+            }
+            let mut expected: Vec<Range> = list.iter().map(|(range, _)| *range).collect();
+            expected.sort();
+            let mut actual = ranges(&tree);
+            actual.sort();
+            assert_eq!(actual, expected);
+
+            let point = random(220);
+            let containing = expected.iter().filter(|(l, r)| *l <= point && point < *r).count();
+            assert_eq!(tree.query_point(point).len(), containing);
+            assert_eq!(tree.count_before(point), expected.iter().filter(|(l, _)| *l < point).count());
+        }
     }
 }
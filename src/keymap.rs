@@ -0,0 +1,298 @@
+extern crate ncurses;
+use ncurses::*;
+use std::char;
+
+pub const ESC_DELAY: i32 = 50; // Milliseconds to wait after Esc for the rest of an Alt key or escape sequence
+
+// Maps keys to the editor's commands. The defaults can be changed with
+// (bind ...) and (unbind ...) in the config file (see config-file-spec.txt)
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Key {
+    Char(char), // Typed characters and Ctrl keys ('\u{0001}' is Ctrl-A)
+    Code(i32), // ncurses keycodes: arrows (with Shift or Ctrl), Home, F1, ...
+    Meta(char) // Alt (or Esc quickly followed by a character), kept lowercase
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    Up,
+    Down,
+    Left,
+    Right,
+    SelectUp,
+    SelectDown,
+    SelectLeft,
+    SelectRight,
+    FastUp,
+    FastDown,
+    FastLeft,
+    FastRight,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    BufferStart,
+    BufferEnd,
+    Newline,
+    Tab,
+    Backspace,
+    Delete,
+    Undo,
+    Redo,
+    Quit,
+    Save,
+    Open,
+    Clipboard,
+    Copy,
+    Cut,
+    Paste,
+    SetMark,
+    Find,
+    FindNext,
+    FindPrevious,
+    NextBuffer,
+    PreviousBuffer,
+    BufferList,
+    GoToLine,
+    LineNumbers
+}
+
+// Names of the commands in the config file
+const COMMAND_NAMES: &[(&str, Command)] = &[
+    ("up", Command::Up),
+    ("down", Command::Down),
+    ("left", Command::Left),
+    ("right", Command::Right),
+    ("select-up", Command::SelectUp),
+    ("select-down", Command::SelectDown),
+    ("select-left", Command::SelectLeft),
+    ("select-right", Command::SelectRight),
+    ("fast-up", Command::FastUp),
+    ("fast-down", Command::FastDown),
+    ("fast-left", Command::FastLeft),
+    ("fast-right", Command::FastRight),
+    ("page-up", Command::PageUp),
+    ("page-down", Command::PageDown),
+    ("home", Command::Home),
+    ("end", Command::End),
+    ("buffer-start", Command::BufferStart),
+    ("buffer-end", Command::BufferEnd),
+    ("newline", Command::Newline),
+    ("tab", Command::Tab),
+    ("backspace", Command::Backspace),
+    ("delete", Command::Delete),
+    ("undo", Command::Undo),
+    ("redo", Command::Redo),
+    ("quit", Command::Quit),
+    ("save", Command::Save),
+    ("open", Command::Open),
+    ("clipboard", Command::Clipboard),
+    ("copy", Command::Copy),
+    ("cut", Command::Cut),
+    ("paste", Command::Paste),
+    ("set-mark", Command::SetMark),
+    ("find", Command::Find),
+    ("find-next", Command::FindNext),
+    ("find-previous", Command::FindPrevious),
+    ("next-buffer", Command::NextBuffer),
+    ("previous-buffer", Command::PreviousBuffer),
+    ("buffer-list", Command::BufferList),
+    ("go-to-line", Command::GoToLine),
+    ("line-numbers", Command::LineNumbers)
+];
+
+// Names of the keycodes, for the config file and the control bar
+const KEY_NAMES: &[(&str, i32)] = &[
+    ("Up", KEY_UP),
+    ("Down", KEY_DOWN),
+    ("Left", KEY_LEFT),
+    ("Right", KEY_RIGHT),
+    ("S-Up", crate::KEY_SUP),
+    ("S-Down", crate::KEY_SDOWN),
+    ("S-Left", KEY_SLEFT),
+    ("S-Right", KEY_SRIGHT),
+    ("C-Up", crate::KEY_CUP),
+    ("C-Down", crate::KEY_CDOWN),
+    ("C-Left", crate::KEY_CLEFT),
+    ("C-Right", crate::KEY_CRIGHT),
+    ("Home", KEY_HOME),
+    ("End", KEY_END),
+    ("C-Home", crate::KEY_CHOME),
+    ("C-End", crate::KEY_CEND),
+    ("PageUp", KEY_PPAGE),
+    ("PageDown", KEY_NPAGE),
+    ("Insert", KEY_IC),
+    ("Delete", KEY_DC),
+    ("Backspace", KEY_BACKSPACE)
+];
+
+// Keys bound to commands, newest first, so that the key shown for a
+// command in the control bar is the one most recently bound to it
+pub struct Keymap {
+    bindings: Vec<(Key, Command)>
+}
+
+pub fn default_keymap() -> Keymap {
+    let bindings = vec![
+        (Key::Code(KEY_UP), Command::Up),
+        (Key::Code(KEY_DOWN), Command::Down),
+        (Key::Code(KEY_LEFT), Command::Left),
+        (Key::Code(KEY_RIGHT), Command::Right),
+        (Key::Code(crate::KEY_SUP), Command::SelectUp),
+        (Key::Code(crate::KEY_SDOWN), Command::SelectDown),
+        (Key::Code(KEY_SLEFT), Command::SelectLeft),
+        (Key::Code(KEY_SRIGHT), Command::SelectRight),
+        (Key::Code(crate::KEY_CUP), Command::FastUp),
+        (Key::Code(crate::KEY_CDOWN), Command::FastDown),
+        (Key::Code(crate::KEY_CLEFT), Command::FastLeft),
+        (Key::Code(crate::KEY_CRIGHT), Command::FastRight),
+        (Key::Code(KEY_PPAGE), Command::PageUp),
+        (Key::Code(KEY_NPAGE), Command::PageDown),
+        (Key::Code(KEY_HOME), Command::Home),
+        (Key::Code(KEY_END), Command::End),
+        (Key::Code(crate::KEY_CHOME), Command::BufferStart),
+        (Key::Code(crate::KEY_CEND), Command::BufferEnd),
+        (Key::Char('\r'), Command::Newline),
+        (Key::Char('\t'), Command::Tab),
+        (Key::Code(KEY_BACKSPACE), Command::Backspace),
+        (Key::Char('\u{0008}'), Command::Backspace), // Ctrl-H
+        (Key::Code(KEY_DC), Command::Delete),
+        (Key::Char('\u{0001}'), Command::Undo), // Ctrl-A
+        (Key::Char('\u{001A}'), Command::Redo), // Ctrl-Z
+        (Key::Char('\u{0018}'), Command::Quit), // Ctrl-X
+        (Key::Char('\u{000F}'), Command::Save), // Ctrl-O
+        (Key::Char('\u{0012}'), Command::Open), // Ctrl-R
+        (Key::Char('\u{0010}'), Command::Clipboard), // Ctrl-P
+        (Key::Char('\u{000A}'), Command::Copy), // Ctrl-J
+        (Key::Char('\u{000B}'), Command::Cut), // Ctrl-K
+        (Key::Char('\u{0015}'), Command::Paste), // Ctrl-U
+        (Key::Char('\u{000C}'), Command::SetMark), // Ctrl-L
+        (Key::Char('\u{0017}'), Command::Find), // Ctrl-W
+        (Key::Meta('w'), Command::FindNext),
+        (Key::Meta('q'), Command::FindPrevious),
+        (Key::Meta('.'), Command::NextBuffer),
+        (Key::Meta('>'), Command::NextBuffer),
+        (Key::Meta(','), Command::PreviousBuffer),
+        (Key::Meta('<'), Command::PreviousBuffer),
+        (Key::Meta('b'), Command::BufferList),
+        (Key::Char('\u{001F}'), Command::GoToLine), // Ctrl-/
+        (Key::Meta('n'), Command::LineNumbers)
+    ];
+    Keymap { bindings }
+}
+
+impl Keymap {
+    pub fn bind(&mut self, key: Key, command: Option<Command>) {
+        // Binds key to command, replacing what it was bound to; None unbinds it
+        self.bindings.retain(|(k, _)| *k != key);
+        if let Some(command) = command {
+            self.bindings.insert(0, (key, command));
+        }
+    }
+
+    pub fn lookup(&self, key: Key) -> Option<Command> {
+        self.bindings.iter().find(|(k, _)| *k == key).map(|(_, command)| *command)
+    }
+
+    pub fn hints(&self, items: &[(Command, &str)], separator: &str) -> String {
+        // Lists the keys for the given commands, like "[^X] Quit", leaving out unbound commands
+        items.iter().filter_map(|(command, label)| {
+            let (key, _) = self.bindings.iter().find(|(_, c)| c == command)?;
+            Some(format!("[{}] {}", key_name(*key), label))
+        }).collect::<Vec<String>>().join(separator)
+    }
+}
+
+pub fn read_key(window: WINDOW) -> Option<Key> {
+    // Reads a key, putting together Esc sequences and folding the different
+    // codes terminals send for Backspace and Enter into one
+    match wget_wch(window)? {
+        WchResult::Char(char_code) => match char::from_u32(char_code)? {
+            '\u{001B}' => {
+                // Alt sends Esc and the key together, so a lone Esc is one with nothing right after it
+                wtimeout(window, ESC_DELAY);
+                let next = wget_wch(window);
+                wtimeout(window, -1);
+                match next {
+                    Some(WchResult::Char(char_code)) => char::from_u32(char_code).map(|c| Key::Meta(c.to_ascii_lowercase())),
+                    Some(WchResult::KeyCode(code)) => {
+                        // Esc then e.g. an arrow key -> the arrow key is read next time
+                        ungetch(code);
+                        Some(Key::Char('\u{001B}'))
+                    },
+                    None => Some(Key::Char('\u{001B}')) // Esc on its own
+                }
+            },
+            '\u{007F}' => Some(Key::Code(KEY_BACKSPACE)),
+            c => Some(Key::Char(c))
+        },
+        WchResult::KeyCode(KEY_ENTER) => Some(Key::Char('\r')),
+        WchResult::KeyCode(code) => Some(Key::Code(modified_key_code(code)))
+    }
+}
+
+fn modified_key_code(code: i32) -> i32 {
+    // Ctrl-modified keys get their codes from the terminfo entry, so they differ
+    // between terminals. They're matched by name instead, onto the codes used here
+    match keyname(code).as_deref() {
+        Some("kUP5") => crate::KEY_CUP,
+        Some("kDN5") => crate::KEY_CDOWN,
+        Some("kLFT5") => crate::KEY_CLEFT,
+        Some("kRIT5") => crate::KEY_CRIGHT,
+        Some("kHOM5") => crate::KEY_CHOME,
+        Some("kEND5") => crate::KEY_CEND,
+        _ => code
+    }
+}
+
+pub fn parse_command(name: &str) -> Option<Command> {
+    COMMAND_NAMES.iter().find(|(n, _)| *n == name).map(|(_, command)| *command)
+}
+
+pub fn parse_key(name: &str) -> Option<Key> {
+    // Reads a key written like "^X", "C-x", "M-b", "Enter", "S-Up", "F5" or "a"
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(c), None, _) => Some(Key::Char(c)),
+        (Some('^'), Some(c), None) => ctrl_char(c).map(Key::Char),
+        (Some('C'), Some('-'), Some(c)) | (Some('c'), Some('-'), Some(c)) if name.chars().count() == 3 => ctrl_char(c).map(Key::Char),
+        (Some('M'), Some('-'), Some(c)) | (Some('m'), Some('-'), Some(c)) if name.chars().count() == 3 => Some(Key::Meta(c.to_ascii_lowercase())),
+        _ => match name.to_ascii_lowercase().as_str() {
+            "enter" => Some(Key::Char('\r')),
+            "tab" => Some(Key::Char('\t')),
+            "space" => Some(Key::Char(' ')),
+            lower => match lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                Some(n) if (1..=12).contains(&n) => Some(Key::Code(KEY_F(n))),
+                _ => KEY_NAMES.iter().find(|(n, _)| n.to_ascii_lowercase() == lower).map(|(_, code)| Key::Code(*code))
+            }
+        }
+    }
+}
+
+pub fn key_name(key: Key) -> String {
+    // Writes a key the way parse_key reads it, with Ctrl keys as ^X
+    match key {
+        Key::Char('\r') => "Enter".to_string(),
+        Key::Char('\t') => "Tab".to_string(),
+        Key::Char(' ') => "Space".to_string(),
+        Key::Char('\u{001F}') => "^/".to_string(),
+        Key::Char(c) if (c as u32) < 0x20 => format!("^{}", char::from_u32(c as u32 + 0x40).unwrap_or('?')),
+        Key::Char(c) => c.to_string(),
+        Key::Meta(c) => format!("M-{}", c.to_ascii_uppercase()),
+        Key::Code(code) => match KEY_NAMES.iter().find(|(_, c)| *c == code) {
+            Some((name, _)) => name.to_string(),
+            None if code > KEY_F0 && code <= KEY_F0 + 12 => format!("F{}", code - KEY_F0),
+            None => format!("<{}>", code)
+        }
+    }
+}
+
+fn ctrl_char(c: char) -> Option<char> {
+    // Gets the character a terminal sends for Ctrl and c
+    match c.to_ascii_uppercase() {
+        '/' => Some('\u{001F}'), // Same as Ctrl-_
+        upper @ '@'..='_' => char::from_u32(upper as u32 - 0x40),
+        _ => None
+    }
+}
@@ -5,10 +5,10 @@ use std::env;
 use std::io::{Read, Write};
 use std::fs;
 use std::io;
-use std::cmp::min;
-use std::path::Path;
+use std::cmp::{min, max};
+use std::path::{Path, PathBuf};
 use std::process;
-use regex::Regex;
+use std::os::unix::fs::{self as unix_fs, MetadataExt};
 mod gap_buffer;
 //mod lines;
 //mod nc;
@@ -17,7 +17,14 @@ mod gapnc;
 mod colors;
 mod syntax_highlighting;
 mod syntax_highlighting_demo;
-//mod interval_tree; // WIP
+mod config;
+mod nanorc;
+mod search;
+mod interval_tree;
+mod prompt;
+mod browser;
+mod keymap;
+mod file_format;
 
 // Missing keycodes
 // Shift Arrow
@@ -30,28 +37,142 @@ const KEY_CLEFT: i32 = 554;
 const KEY_CDOWN: i32 = 534;
 const KEY_CUP: i32 = 575;
 
+// Control Home/End
+const KEY_CHOME: i32 = 544;
+const KEY_CEND: i32 = 539;
+
 // File IO
 
-fn open_file(window: WINDOW, path: &str) -> gapnc::GapEditor {
-    // Open file given in argument, and return editor created from file contents
+fn open_file(window: WINDOW, path: &str) -> Result<Buffer, io::Error> {
+    // Open file given in argument, and return a buffer created from file contents
+    // Files that aren't UTF-8 are read as Latin-1, so that they save back as they were
 
-    let reader = fs::File::open(Path::new(path));
-    let mut file = reader.ok().expect("Unable to open file");
+    let (contents, encoding) = file_format::Encoding::decode(fs::read(Path::new(path))?);
     //nc::Editor::from_file(file, window)
-    gapnc::GapEditor::from_file(file, window)
+    Ok(Buffer {
+        editor: gapnc::GapEditor::from_string(&contents, window),
+        path: Some(path.to_string()),
+        new_file: false,
+        encoding,
+        line_ending: file_format::LineEnding::detect(&contents)
+    })
+}
+
+fn apply_config(editor: &mut gapnc::GapEditor, path: Option<&str>) -> Result<(), config::ConfigError> {
+    // Apply the user's config (~/.ranorc), evaluated for this file
+    // If it has an error, the defaults are applied and the error is returned to be shown
+    let config_target = config::Target::new(path.map(PathBuf::from), editor.first_line());
+    let (mut config, mut result) = match config::load_user_config(&config_target) {
+        Ok(config) => (config, Ok(())),
+        Err(e) => (config::default(), Err(e))
+    };
+    if !config.skipped.is_empty() {
+        // The rest of the config still applies
+        result = Err(config.skipped.remove(0));
+    }
+    editor.set_tab_size(config.tabsize);
+    editor.set_line_numbers(config.linenumbers);
+    match config.highlight_rules() {
+        Some(highlight_rules) => editor.set_highlight_rules(highlight_rules),
+        None => editor.clear_highlight_rules() // E.g. saved under a name with no rules
+    }
+    result
+}
+
+fn load_keymap(keymap: &mut keymap::Keymap) -> Result<(), config::ConfigError> {
+    // Apply the key bindings in the user's config to the keymap
+    // They're shared by all buffers, so the config is evaluated without a file
+    // If it has an error, the keymap is left as it is and the error is returned to be shown
+    let config_target = config::Target::new(None, String::new());
+    let config = config::load_user_config(&config_target)?;
+    for (key, command) in config.bindings {
+        keymap.bind(key, command);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WriteMode {
+    Overwrite,
+    Append,
+    Prepend
 }
 
 //fn save_to_file(filename: String, editor: &nc::Editor) -> Result<(), io::Error>{
-fn save_to_file(filename: String, editor: &gapnc::GapEditor) -> Result<(), io::Error>{
-    // Saves the file to the given path
-    let mut maybe_file = fs::OpenOptions::new().write(true).create(true).open(&filename);
-    match maybe_file {
-        Ok(mut file) => {
-            file.write(editor.export().as_bytes());
+fn save_to_file(filename: String, contents: &[u8], mode: WriteMode) -> Result<(), io::Error>{
+    // Saves contents to the given path, replacing the file or adding to either end of it
+    // The buffer is written to a temp file next to the target, which then
+    // replaces it, so a failed save never leaves a half written file behind.
+    // If the path is a symlink, the file it points to is replaced, keeping
+    // the mode and owner it had
+    let target = resolve_symlinks(Path::new(&filename));
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+    let name = target.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file name"))?;
+    let temp_path = dir.join(format!(".{}.rano-{}", name.to_string_lossy(), process::id()));
+    let original = fs::metadata(&target).ok();
+
+    let bytes = match mode {
+        WriteMode::Overwrite => contents.to_vec(),
+        WriteMode::Append | WriteMode::Prepend => {
+            let existing = match fs::read(&target) {
+                Ok(existing) => existing,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => { return Err(e); }
+            };
+            if mode == WriteMode::Append {
+                [existing.as_slice(), contents].concat()
+            } else {
+                [contents, existing.as_slice()].concat()
+            }
+        }
+    };
+
+    let result = write_temp_file(&temp_path, &bytes, original.as_ref()).and_then(|_| fs::rename(&temp_path, &target));
+    match result {
+        Ok(()) => {
+            // Make the rename itself durable (not possible on every filesystem)
+            if let Ok(dir) = fs::File::open(&dir) {
+                let _ = dir.sync_all();
+            }
             Ok(())
         },
-        Err(T) => Err(T)
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+fn write_temp_file(temp_path: &Path, bytes: &[u8], original: Option<&fs::Metadata>) -> Result<(), io::Error> {
+    // Writes the contents to a new file, with the original file's permissions
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(temp_path)?;
+    file.write_all(bytes)?;
+    if let Some(original) = original {
+        file.set_permissions(original.permissions())?;
+        // Only root can give files away, so this is best effort
+        let _ = unix_fs::fchown(&file, Some(original.uid()), Some(original.gid()));
     }
+    file.sync_all()
+}
+
+fn resolve_symlinks(path: &Path) -> PathBuf {
+    // Follows path while it's a symlink, to the file that should be written
+    let mut path = path.to_path_buf();
+    for _ in 0..40 { // Same limit as the kernel, in case of loops
+        match fs::read_link(&path) {
+            Ok(link) => {
+                path = match path.parent() {
+                    Some(parent) => parent.join(link),
+                    None => link
+                };
+            },
+            Err(_) => break
+        }
+    }
+    path
 }
 
 fn file_exists(filename: String) -> bool {
@@ -66,6 +187,14 @@ fn file_exists(filename: String) -> bool {
     }
 }
 
+fn same_file(a: &str, b: &str) -> bool {
+    // Check if two paths name the same file
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b
+    }
+}
+
 // Window creators
 
 fn create_editor_window() -> WINDOW {
@@ -94,10 +223,42 @@ fn create_control_bar_window() -> WINDOW {
     window
 }
 
+fn resize_windows(editor_window: WINDOW, ctrl_window: WINDOW) -> bool {
+    // Fits the editor and control bar windows to the terminal, if its size changed
+    // Returns true if they were resized
+
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(stdscr(), &mut max_y, &mut max_x);
+    let mut editor_x = 0;
+    let mut editor_y = 0;
+    getmaxyx(editor_window, &mut editor_y, &mut editor_x);
+
+    if (editor_y + 2, editor_x) == (max_y, max_x) || max_y < 3 {
+        return false;
+    }
+    wresize(editor_window, max_y - 2, max_x);
+    place_control_bar(ctrl_window);
+    true
+}
+
+fn place_control_bar(ctrl_window: WINDOW) {
+    // Fits the control bar to the bottom two lines of the terminal
+    // Prompts use this to stay visible when resized, the editor is fitted after they return
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(stdscr(), &mut max_y, &mut max_x);
+    if max_y < 3 {
+        return;
+    }
+    wresize(ctrl_window, 2, max_x);
+    mvwin(ctrl_window, max_y - 2, 0);
+}
+
 // Window drawers and helpers
 
-fn draw_control_bar(window: WINDOW) {
-    // Draws control sequences
+fn draw_control_bar(window: WINDOW, keymap: &keymap::Keymap) {
+    // Draws control sequences, with the keys they're bound to
 
     let mut max_x = 0;
     let mut max_y = 0;
@@ -107,426 +268,689 @@ fn draw_control_bar(window: WINDOW) {
     wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
     mvwaddstr(window, 0, 0, &" ".repeat(max_x as usize)).unwrap();
     wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    let ctrl_string = "[^X] Quit\t[^O] Save\t[^K] Cut\t[^J] Copy\t[^U] Paste\t[^P] Clipboard\t[^/] Go To Line\t[^A] Undo\t[^Z] Redo\t[^L] Set Mark".to_string();
+    let ctrl_string = keymap.hints(&[
+        (keymap::Command::Quit, "Quit"),
+        (keymap::Command::Save, "Save"),
+        (keymap::Command::Open, "Open"),
+        (keymap::Command::Cut, "Cut"),
+        (keymap::Command::Copy, "Copy"),
+        (keymap::Command::Paste, "Paste"),
+        (keymap::Command::Clipboard, "Clipboard"),
+        (keymap::Command::GoToLine, "Go To Line"),
+        (keymap::Command::Undo, "Undo"),
+        (keymap::Command::Redo, "Redo"),
+        (keymap::Command::SetMark, "Set Mark")
+    ], "\t");
     let ctrl_string_len = ctrl_string.len();
-    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat(max_x as usize - ctrl_string_len))).unwrap();
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
+}
+
+// An open file (or unnamed scratch buffer), with its own undo history and selection
+struct Buffer {
+    editor: gapnc::GapEditor,
+    path: Option<String>,
+    new_file: bool, // The path didn't exist when it was opened, and the buffer hasn't been saved to it yet
+    encoding: file_format::Encoding, // What it's saved as
+    line_ending: file_format::LineEnding // As of when it was opened or last saved
+}
+
+impl Buffer {
+    fn empty(window: WINDOW, path: Option<String>, new_file: bool) -> Buffer {
+        Buffer {
+            editor: gapnc::GapEditor::empty(window),
+            path,
+            new_file,
+            encoding: file_format::Encoding::Utf8,
+            line_ending: file_format::LineEnding::Lf
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self.path {
+            Some(path) => path.clone(),
+            None => "New Buffer".to_string()
+        }
+    }
+
+    fn switch_message(&self) -> String {
+        // Shown when the buffer is switched to
+        if self.new_file {
+            format!("{} (New File)", self.name())
+        } else {
+            self.name()
+        }
+    }
+
+    fn encoding_warning(&self) -> Option<String> {
+        // Shown when a file that isn't UTF-8 is opened
+        match self.encoding {
+            file_format::Encoding::Utf8 => None,
+            encoding => Some(format!("{} isn't valid UTF-8, so it was opened as {}", self.name(), encoding.name()))
+        }
+    }
+}
+
+// Things the main loop does to the buffer list, rather than the current buffer
+enum BufferCommand {
+    Switch(usize),
+    Pick,
+    Open(String),
+    Quit
+}
+
+fn record_save(editor: &mut gapnc::GapEditor, path: &mut Option<String>, new_file: &mut bool, line_ending: &mut file_format::LineEnding,
+               target: WriteTarget) -> Option<String> {
+    // Updates a buffer after save_loop wrote it
+    // Returns the message to show about it, if any
+    if !target.whole_buffer() {
+        // Only part of the buffer, or not the whole file -> still modified
+        let what = if target.selection { "selection" } else { "buffer" };
+        return Some(match target.mode {
+            WriteMode::Overwrite => format!("Wrote {} to {}", what, target.path),
+            WriteMode::Append => format!("Appended {} to {}", what, target.path),
+            WriteMode::Prepend => format!("Prepended {} to {}", what, target.path)
+        });
+    }
+
+    editor.unset_save();
+    *new_file = false;
+    *line_ending = file_format::LineEnding::detect(&editor.export());
+    if path.as_deref().is_some_and(|path| same_file(&target.path, path)) {
+        return None;
+    }
+    // Saved as a new file -> the buffer is that file now
+    let message = format!("Saved as {}", target.path);
+    *path = Some(target.path);
+    match apply_config(editor, path.as_deref()) {
+        Ok(()) => Some(message),
+        Err(e) => Some(format!("{}, but its config has an error: {}", message, e))
+    }
+}
+
+// Where and how save_loop wrote the buffer
+struct WriteTarget {
+    path: String,
+    mode: WriteMode,
+    selection: bool // Only the selected text was written
+}
+
+impl WriteTarget {
+    fn whole_buffer(&self) -> bool {
+        // Checks if the file now holds exactly the buffer
+        self.mode == WriteMode::Overwrite && !self.selection
+    }
 }
 
 //fn save_loop(window: WINDOW, editor: &nc::Editor, path: &String) -> bool{
-fn save_loop(window: WINDOW, editor: &gapnc::GapEditor, path: &String) -> bool{
+fn save_loop(window: WINDOW, editor_window: WINDOW, editor: &mut gapnc::GapEditor, path: Option<&str>, encoding: file_format::Encoding,
+             history: &mut prompt::History, allow_partial: bool) -> Result<Option<WriteTarget>, String>{
     // Runs the UI process of saving
-    // Returns true if actually saved
+    // Returns where it was saved if actually saved (which can be a new path,
+    // if the name was changed), or the error to show if saving failed, in
+    // which case the buffer is marked modified, so that closing it asks again
+    // Unless allow_partial is false, the buffer can also be appended or
+    // prepended to the file, and just the selection can be written
+
+    let mut mode = WriteMode::Overwrite;
+    let mut selection = false;
+    let action = if allow_partial {
+        "[Enter] Save\t[^A] Append\t[^P] Prepend\t[^S] Selection Only"
+    } else {
+        "[Enter] Save"
+    };
+    let mut input = prompt::Prompt::new(save_label(mode, selection), path.unwrap_or("")); // Unnamed buffers start blank
+
+    loop {
+        let filename = match filename_loop(window, editor_window, &mut input, action, history, &mut |input, c| {
+            match c {
+                '\u{0001}' if allow_partial => {
+                    // Ctrl-A -> Append
+                    mode = if mode == WriteMode::Append { WriteMode::Overwrite } else { WriteMode::Append };
+                },
+                '\u{0010}' if allow_partial => {
+                    // Ctrl-P -> Prepend
+                    mode = if mode == WriteMode::Prepend { WriteMode::Overwrite } else { WriteMode::Prepend };
+                },
+                '\u{0013}' if allow_partial && editor.has_selection() => {
+                    // Ctrl-S -> Selection only
+                    selection = !selection;
+                },
+                _ => { return false; }
+            }
+            input.set_label(save_label(mode, selection));
+            true
+        }) {
+            Some(filename) if filename.is_empty() => {
+                beep();
+                continue;
+            },
+            Some(filename) => filename,
+            None => { return Ok(None); }
+        };
+        // Replacing another file (or this one with just the selection) -> make sure it's meant
+        if mode == WriteMode::Overwrite && (selection || !path.is_some_and(|path| same_file(&filename, path))) && file_exists(filename.clone()) {
+            match yes_no_loop(window, &format!("File \"{}\" exists, OVERWRITE?", filename)) {
+                Some(true) => {},
+                Some(false) => { continue; }, // Pick another name
+                None => { return Ok(None); }
+            }
+        }
+        let contents = if selection { editor.export_selection().unwrap_or_default() } else { editor.export() };
+        let target = WriteTarget { path: filename, mode, selection };
+        let result = match encoding.encode(&contents) {
+            Some(bytes) => save_to_file(target.path.clone(), &bytes, mode).map_err(|e| e.to_string()),
+            None => Err(format!("it has characters that {} can't hold", encoding.name()))
+        };
+        return match result {
+            Ok(()) => Ok(Some(target)),
+            Err(e) => {
+                if target.whole_buffer() {
+                    editor.set_save();
+                }
+                Err(format!("Error writing {}: {}", target.path, e))
+            }
+        };
+    }
+}
+
+fn save_label(mode: WriteMode, selection: bool) -> &'static str {
+    // Gets the save prompt for the write mode
+    match (mode, selection) {
+        (WriteMode::Overwrite, false) => "File Name to Write: ",
+        (WriteMode::Append, false) => "File Name to Append to: ",
+        (WriteMode::Prepend, false) => "File Name to Prepend to: ",
+        (WriteMode::Overwrite, true) => "Write Selection to File: ",
+        (WriteMode::Append, true) => "Append Selection to File: ",
+        (WriteMode::Prepend, true) => "Prepend Selection to File: "
+    }
+}
+
+fn filename_loop(window: WINDOW, editor_window: WINDOW, input: &mut prompt::Prompt, action: &str, history: &mut prompt::History,
+                 on_control: &mut dyn FnMut(&mut prompt::Prompt, char) -> bool) -> Option<String> {
+    // Handle UI sequence for entering a path
+    // Tab completes the path (listing the candidates over the editor if it's
+    // ambiguous), and Ctrl-T opens the file browser to pick it instead
+    // Other control keys go to on_control, which returns false if it doesn't use them
 
     let mut max_x = 0;
     let mut max_y = 0;
     getmaxyx(window, &mut max_y, &mut max_x);
 
-    let mut cur_x = 0;
-    let mut cur_y = 0;
-
     curs_set(CURSOR_VISIBILITY::CURSOR_VERY_VISIBLE);
 
-    let ctrl_string = "[Enter] Save\t[^C] Cancel".to_string();
+    let ctrl_string = format!("{}\t[Tab] Complete\t[^T] Browse\t[^C] Cancel", action);
     let ctrl_string_len = ctrl_string.len();
-    let file_input_string = "File Name to Write: ".to_string();
-    let file_input_string_len = file_input_string.len();
-    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat(max_x as usize - ctrl_string_len))).unwrap();
-    wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    mvwaddstr(window, 0, 0, &(file_input_string + &" ".repeat(max_x as usize - file_input_string_len))).unwrap();
-    wmove(window, 0, file_input_string_len as i32);
-    waddstr(window, &path).unwrap();
-    wrefresh(window);
-
-    let left_limit = file_input_string_len as i32; // If cur_x == left_limit, prevent deletion
-    let right_limit = max_x - 1; // If cur_x == max_x, prevent character addition
+    mvwaddstr(window, 1, 0, &(ctrl_string.clone() + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
 
-    let mut filename_buffer = path.clone();
+    let mut listing = None; // Completion candidates, shown until the next key
 
-    let mut ch;
-    let mut ret: bool = false;
+    let mut ret = None;
     loop {
-        ch = wget_wch(window);
-        getyx(window, &mut cur_y, &mut cur_x); // Get current cursor location
-        match ch {
-            Some(WchResult::Char(char_code)) => {
-                let c = char::from_u32(char_code as u32).expect("Invalid char");
-                match c {
-                    '\u{0003}' => {
-                        // Ctrl-C
-                        break;
-                    },
-                    '\u{007F}' => {
-                        // Backspace
-
-                        // Check if can't delete further
-                        if cur_x == left_limit {
-                            beep();
-                            continue;
-                        }
-
-                        // We are essentially replacing the characters with spaces
-                        wmove(window, cur_y, cur_x - 1);
-                        wdelch(window);
-                        winsch(window, ' ' as chtype);
-                        filename_buffer.pop();
-                    },
-                    '\r' => {
-                        // Enter
-                        save_to_file(filename_buffer, editor);
-                        ret = true;
-                        break;
-                    },
-                    '\u{001C}' => {
-                        // Ctrl-\
-                    },
-                    '\u{0001}'..='\u{001F}' => {
-                        beep();
-                    },
-                    _ => {
-                        if cur_x == right_limit {
-                            beep();
-                            continue;
-                        }
-
-                        waddch(window, c as chtype);
-                        filename_buffer.push(c);
-                    }
+        input.draw(window);
+        let event = input.read(window, history);
+        if let Some(listing_window) = listing.take() {
+            browser::close_window(listing_window, editor_window);
+        }
+        match event {
+            prompt::PromptEvent::Submit => {
+                ret = Some(input.text());
+                break;
+            },
+            prompt::PromptEvent::Cancel => { break; },
+            prompt::PromptEvent::Control('\t') => {
+                // Tab -> Complete
+                let (completed, candidates) = browser::complete_path(&input.text());
+                if completed == input.text() && candidates.is_empty() {
+                    beep();
+                }
+                input.set_text(&completed);
+                if !candidates.is_empty() {
+                    listing = Some(browser::show_listing(editor_window, &candidates));
                 }
             },
-            _ => {break;}
+            prompt::PromptEvent::Control('\u{0014}') => {
+                // Ctrl-T -> Browse
+                if let Some(path) = browser::browse(editor_window, window, &input.text()) {
+                    input.set_text(&path);
+                }
+                mvwaddstr(window, 1, 0, &(ctrl_string.clone() + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
+            },
+            prompt::PromptEvent::Control(c) if !on_control(input, c) => { beep(); },
+            prompt::PromptEvent::Resized => { place_control_bar(window); },
+            _ => {}
         }
-        wrefresh(window);
     }
-    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
     curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
     ret
 }
 
 //fn exit_loop(window: WINDOW, editor: &nc::Editor, path: &String) -> bool {
-fn exit_loop(window: WINDOW, editor: &gapnc::GapEditor, path: &String) -> bool {
+fn exit_loop(window: WINDOW, editor_window: WINDOW, buffer: &mut Buffer, history: &mut prompt::History) -> Result<bool, String> {
     // Handle UI sequence for exiting when you haven't saved
+    // Returns true if the buffer can be closed, or the error if saving it failed
+    let Buffer { editor, path, new_file, encoding, line_ending } = buffer;
+
+    let question = match path {
+        Some(path) => format!("Save modified buffer \"{}\"?", path),
+        None => "Save modified buffer?".to_string()
+    };
+    match yes_no_loop(window, &question) {
+        Some(true) => {
+            // Cancel the exit if the save is also cancelled
+            match save_loop(window, editor_window, editor, path.as_deref(), *encoding, history, false)? {
+                Some(target) => {
+                    record_save(editor, path, new_file, line_ending, target);
+                    Ok(true)
+                },
+                None => Ok(false)
+            }
+        },
+        Some(false) => Ok(true),
+        None => Ok(false)
+    }
+}
+
+fn yes_no_loop(window: WINDOW, question: &str) -> Option<bool> {
+    // Handle UI sequence for answering a yes/no question
+    // Returns None if cancelled
 
     let mut max_x = 0;
     let mut max_y = 0;
     getmaxyx(window, &mut max_y, &mut max_x);
 
-    let mut cur_x = 0;
-    let mut cur_y = 0;
-
     curs_set(CURSOR_VISIBILITY::CURSOR_VERY_VISIBLE);
 
     let ctrl_string = "[Y] Yes\t[N] No\t[^C] Cancel".to_string();
     let ctrl_string_len = ctrl_string.len();
-    let buffer_query_string = "Save modified buffer?".to_string();
-    let buffer_query_string_len = buffer_query_string.len();
-    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat(max_x as usize - ctrl_string_len))).unwrap();
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
     wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    mvwaddstr(window, 0, 0, &(buffer_query_string + &" ".repeat(max_x as usize - buffer_query_string_len))).unwrap();
-    wmove(window, 0, buffer_query_string_len as i32 + 1);
+    mvwaddstr(window, 0, 0, &pad(question.to_string(), max(max_x as usize, question.len()))).unwrap();
+    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+    wmove(window, 0, min(question.len() as i32 + 1, max_x - 1));
     wrefresh(window);
 
-
-    let mut ch = wget_wch(window);
     loop {
-        match ch {
+        match wget_wch(window) {
             Some(WchResult::Char(char_code)) => {
-                let c = char::from_u32(char_code as u32).expect("Invalid character");
+                let c = char::from_u32(char_code).expect("Invalid character");
                 match c {
-                    'y' => {
-                        wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-                        return save_loop(window, editor, path); // Cancel the exit if the save is also cancelled
-                    },
-                    'n' => {
-                        return true;
-                    },
-                    '\u{0003}' => {
-                        return false;
-                    },
-                    _ => {
-                        beep();
-                    },
+                    'y' | 'Y' => { return Some(true); },
+                    'n' | 'N' => { return Some(false); },
+                    '\u{0003}' => { return None; },
+                    _ => { beep(); }
                 }
             },
-            _ => {
-                beep();
-            }
+            _ => { beep(); }
         }
-        ch = wget_wch(window);
     }
 }
 
-fn go_to_line_loop(window: WINDOW, editor: &gapnc::GapEditor) -> Option<usize> {
+fn go_to_line_loop(window: WINDOW, editor: &gapnc::GapEditor, history: &mut prompt::History) -> Option<usize> {
     // Handle UI sequence for going to a particular line
 
     let mut max_x = 0;
     let mut max_y = 0;
     getmaxyx(window, &mut max_y, &mut max_x);
 
-    let mut cur_x = 0;
-    let mut cur_y = 0;
-
     curs_set(CURSOR_VISIBILITY::CURSOR_VERY_VISIBLE);
 
     let ctrl_string = "[Enter] Go To Line\t[^C] Cancel".to_string();
     let ctrl_string_len = ctrl_string.len();
-    let lineno_input_string = "Go to Line Number: ".to_string();
-    let lineno_input_string_len = lineno_input_string.len();
-    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat(max_x as usize - ctrl_string_len))).unwrap();
-    wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    mvwaddstr(window, 0, 0, &(lineno_input_string + &" ".repeat(max_x as usize - lineno_input_string_len))).unwrap();
-    wmove(window, 0, lineno_input_string_len as i32);
-    wrefresh(window);
-
-    let left_limit = lineno_input_string_len as i32; // If cur_x == left_limit, prevent deletion
-    let right_limit = max_x - 1; // If cur_x == max_x, prevent character addition
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
 
-    let mut lineno_buffer = String::new();
+    let mut input = prompt::Prompt::new("Go to Line Number: ", "");
 
-    let mut ch;
-    let mut ret: bool = false;
+    let mut ret = None;
     loop {
-        ch = wget_wch(window);
-        getyx(window, &mut cur_y, &mut cur_x); // Get current cursor location
-        match ch {
-            Some(WchResult::Char(char_code)) => {
-                let c = char::from_u32(char_code as u32).expect("Invalid char");
-                match c {
-                    '\u{0003}' => {
-                        // Ctrl-C
+        input.draw(window);
+        match input.read(window, history) {
+            prompt::PromptEvent::Submit => {
+                match input.text().parse::<usize>() {
+                    Ok(lineno) => {
+                        ret = Some(lineno);
                         break;
                     },
-                    '\u{007F}' => {
-                        // Backspace
-
-                        // Check if can't delete further
-                        if cur_x == left_limit {
-                            beep();
-                            continue;
-                        }
-
-                        // We are essentially replacing the characters with spaces
-                        wmove(window, cur_y, cur_x - 1);
-                        wdelch(window);
-                        winsch(window, ' ' as chtype);
-                        lineno_buffer.pop();
-                    },
-                    '\r' => {
-                        // Enter
-                        match lineno_buffer.parse::<usize>() {
-                            Ok(lineno) => { return Some(lineno) },
-                            Err(e) => {
-                                // We can't parse the buffer, throw an error
-                                beep();
-                            }
-                        }
-                        //
-                    },
-                    '\u{0001}'..='\u{001F}' => {
+                    Err(_) => {
+                        // We can't parse the buffer, throw an error
                         beep();
-                    },
-                    _ => {
-                        if cur_x == right_limit {
-                            beep();
-                            continue;
-                        }
-
-                        waddch(window, c as chtype);
-                        lineno_buffer.push(c);
                     }
                 }
             },
-            _ => {break;}
+            prompt::PromptEvent::Cancel => { break; },
+            prompt::PromptEvent::Control(_) => { beep(); },
+            prompt::PromptEvent::Resized => { place_control_bar(window); },
+            _ => {}
         }
-        wrefresh(window);
     }
-    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
     curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
-    None
+    ret
 }
 
-fn search_loop(window: WINDOW, editor: &gapnc::GapEditor) -> Option<(String, Option<String>)> {
-    // Handle UI sequence for going to a particular line
-    // Returns Option<Search>, where Search = (String, Option<Replace>)
+fn search_loop(window: WINDOW, editor_window: WINDOW, editor: &mut gapnc::GapEditor,
+               history: &mut prompt::History, replace_history: &mut prompt::History) -> Option<(search::SearchQuery, Option<String>)> {
+    // Handle UI sequence for searching
+    // Returns Option<Search>, where Search = (SearchQuery, Option<Replace>)
+    // The search runs as the query is typed; cancelling puts the cursor back
 
     let mut max_x = 0;
     let mut max_y = 0;
     getmaxyx(window, &mut max_y, &mut max_x);
 
-    let mut cur_x = 0;
-    let mut cur_y = 0;
-
     curs_set(CURSOR_VISIBILITY::CURSOR_VERY_VISIBLE);
-    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
 
-    let ctrl_string = "[Enter] Find\t[^R] Replace\t[^F] Search Regex\t[^C] Cancel".to_string();
+    let ctrl_string = "[Enter] Find\t[^R] Replace\t[^F] Regex\t[^E] Ignore Case\t[^B] Whole Word\t[^S] In Selection\t[^C] Cancel".to_string();
     let ctrl_string_len = ctrl_string.len();
-    let search_input_string = "Search for String: ".to_string();
-    let search_input_string_len = search_input_string.len();
-    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat(max_x as usize - ctrl_string_len))).unwrap();
-    wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    mvwaddstr(window, 0, 0, &(search_input_string + &" ".repeat(max_x as usize - search_input_string_len))).unwrap();
-    wmove(window, 0, search_input_string_len as i32);
-    wrefresh(window);
-
-    let left_limit = search_input_string_len as i32; // If cur_x == left_limit, prevent deletion
-    let right_limit = max_x - 1; // If cur_x == max_x, prevent character addition
+    let mut query = search::SearchQuery::new(String::new(), false); // Holds the options until the query is entered
+    let mut in_selection = false;
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
 
-    let mut search_buffer = String::new();
+    let mut input = prompt::Prompt::new(&search_label(&query, in_selection), "");
+    let mut valid = true; // Whether the query compiles
+    // Taken before the live search starts moving the cursor (which moves an active selection)
+    let start_pos = editor.pos();
+    let selection = selection_scope(editor);
 
-    let mut ch;
-    let mut ret: bool = false;
     loop {
-        ch = wget_wch(window);
-        getyx(window, &mut cur_y, &mut cur_x); // Get current cursor location
-        match ch {
-            Some(WchResult::Char(char_code)) => {
-                let c = char::from_u32(char_code as u32).expect("Invalid char");
-                match c {
-                    '\u{0003}' => {
-                        // Ctrl-C
-                        break;
-                    },
-                    '\u{007F}' => {
-                        // Backspace
-
-                        // Check if can't delete further
-                        if cur_x == left_limit {
-                            beep();
-                            continue;
-                        }
-
-                        // We are essentially replacing the characters with spaces
-                        wmove(window, cur_y, cur_x - 1);
-                        wdelch(window);
-                        winsch(window, ' ' as chtype);
-                        search_buffer.pop();
-                    },
-                    '\r' => {
-                        // Enter
-                        let search_string = escape_regex(search_buffer);
-                        return match search_string {
-                            Some(escaped_search_string) => Some((escaped_search_string, None)),
-                            None => None
-                        };
-                        //return Some(escape_regex(search_buffer)?);
-                    },
-                    '\u{0006}' => {
-                        // Ctrl-F -> Regex
-                        return Some((search_buffer, None));
-                    },
-                    '\u{0012}' => {
-                        // Ctrl-R -> Replace
-                        let search_string = escape_regex(search_buffer)?;
-                        return match replace_loop(window, &editor, search_string.clone()) {
-                            Some(replace_string) => Some((search_string, Some(replace_string))),
-                            None => None
-                        };
-                        //return Some((search_string.clone(), replace_loop(window, &editor, search_string.clone())));
-                    },
-                    '\u{0001}'..='\u{001F}' => {
+        input.draw(window);
+        match input.read(window, history) {
+            prompt::PromptEvent::Submit => {
+                if !valid {
+                    beep();
+                    continue;
+                }
+                return Some((query, None));
+            },
+            prompt::PromptEvent::Cancel => { break; },
+            prompt::PromptEvent::Edited => {},
+            prompt::PromptEvent::Unchanged => { continue; },
+            prompt::PromptEvent::Resized => {
+                place_control_bar(window);
+                continue;
+            },
+            prompt::PromptEvent::Control(c) => match c {
+                '\u{0006}' => { query.regex = !query.regex; }, // Ctrl-F -> Regex
+                '\u{0005}' => { query.ignore_case = !query.ignore_case; }, // Ctrl-E -> Ignore case
+                '\u{0002}' => { query.whole_word = !query.whole_word; }, // Ctrl-B -> Whole word
+                '\u{0013}' if selection.is_some() => { in_selection = !in_selection; }, // Ctrl-S -> Within selection
+                '\u{0012}' => {
+                    // Ctrl-R -> Replace
+                    if !valid {
                         beep();
-                    },
-                    _ => {
-                        if cur_x == right_limit {
-                            beep();
-                            continue;
-                        }
-
-                        waddch(window, c as chtype);
-                        search_buffer.push(c);
+                        continue;
                     }
+                    history.add(&query.text);
+                    return replace_loop(window, &query, replace_history).map(|replace_string| (query, Some(replace_string)));
+                },
+                _ => {
+                    beep();
+                    continue;
                 }
-            },
-            _ => {break;}
+            }
+        }
+
+        // The query or its options changed -> search again
+        query.text = input.text();
+        query.scope = if in_selection { selection } else { None };
+        editor.clear_search();
+        valid = query.text.is_empty() || editor.search_all(&query).is_ok();
+        if !editor.goto_search_hit_from(start_pos) {
+            editor.move_to(start_pos);
+        }
+        werase(editor_window);
+        editor.display_at_frame_cursor();
+        editor.move_cursor_to();
+        wrefresh(editor_window);
+
+        // Show the options, in the error color if the query doesn't compile
+        if valid {
+            input.set_label(&search_label(&query, in_selection));
+            input.set_color(colors::CP_HIGHLIGHT);
+        } else {
+            input.set_label(&format!("[Invalid regex] {}", search_label(&query, in_selection)));
+            input.set_color(colors::CP_ERROR);
         }
-        wrefresh(window);
     }
-    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+
+    // Cancelled
+    editor.clear_search();
+    editor.move_to(start_pos);
     curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
     None
 }
 
-fn replace_loop(window: WINDOW, editor: &gapnc::GapEditor, replace_string: String) -> Option<String> {
-    // Handle UI sequence for going to a particular line
+fn search_label(query: &search::SearchQuery, in_selection: bool) -> String {
+    // Gets the search prompt, listing the options that are on
+    let mut options = Vec::new();
+    if query.ignore_case {
+        options.push("ignore case");
+    }
+    if query.whole_word {
+        options.push("whole word");
+    }
+    if in_selection {
+        options.push("in selection");
+    }
+    let kind = if query.regex { "Regex" } else { "String" };
+    if options.is_empty() {
+        format!("Search for {}: ", kind)
+    } else {
+        format!("Search for {} ({}): ", kind, options.join(", "))
+    }
+}
+
+fn selection_scope(editor: &gapnc::GapEditor) -> Option<(usize, usize)> {
+    // Gets the selection as a Dijkstra range, if anything is selected
+    if !editor.has_selection() {
+        return None;
+    }
+    let (lmark, rmark) = editor.get_select_region();
+    Some((lmark, rmark + 1))
+}
+
+fn replace_loop(window: WINDOW, query: &search::SearchQuery, history: &mut prompt::History) -> Option<String> {
+    // Handle UI sequence for entering the replacement
 
     let mut max_x = 0;
     let mut max_y = 0;
     getmaxyx(window, &mut max_y, &mut max_x);
 
-    let mut cur_x = 0;
-    let mut cur_y = 0;
-
     curs_set(CURSOR_VISIBILITY::CURSOR_VERY_VISIBLE);
-    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
 
     let ctrl_string = "[Enter] Find\t[^C] Cancel".to_string();
     let ctrl_string_len = ctrl_string.len();
-    let replace_input_string = "Replace string with: ".to_string();
-    let replace_input_string_len = replace_input_string.len();
-    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat(max_x as usize - ctrl_string_len))).unwrap();
+    let label = if query.regex {
+        "Replace regex with ($1, ${name} for groups): "
+    } else {
+        "Replace string with: "
+    };
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
+
+    let mut input = prompt::Prompt::new(label, "");
+
+    let mut ret = None;
+    loop {
+        input.draw(window);
+        match input.read(window, history) {
+            prompt::PromptEvent::Submit => {
+                ret = Some(input.text());
+                break;
+            },
+            prompt::PromptEvent::Cancel => { break; },
+            prompt::PromptEvent::Control(_) => { beep(); },
+            prompt::PromptEvent::Resized => { place_control_bar(window); },
+            _ => {}
+        }
+    }
+    curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
+    ret
+}
+
+fn buffer_list_loop(window: WINDOW, editor_window: WINDOW, buffers: &[Buffer], current: usize) -> Option<usize> {
+    // Handle UI sequence for picking a buffer from the list of open ones
+    // The list is shown over the editor; returns the picked buffer's index
+
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(window, &mut max_y, &mut max_x);
+
+    let ctrl_string = "[Enter] Switch\t[Up] Select Previous\t[Down] Select Next\t[^C] Cancel".to_string();
+    let ctrl_string_len = ctrl_string.len();
+    let title_string = format!("Buffers ({} open)", buffers.len());
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
     wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    mvwaddstr(window, 0, 0, &(replace_input_string + &" ".repeat(max_x as usize - replace_input_string_len))).unwrap();
-    wmove(window, 0, replace_input_string_len as i32);
+    mvwaddstr(window, 0, 0, &pad(title_string.clone(), max(max_x as usize, title_string.len()))).unwrap();
+    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
     wrefresh(window);
 
-    let left_limit = replace_input_string_len as i32; // If cur_x == left_limit, prevent deletion
-    let right_limit = max_x - 1; // If cur_x == max_x, prevent character addition
-
-    let mut replace_buffer = String::new();
+    let list_window = browser::overlay_window(editor_window);
+    keypad(list_window, true);
+    let mut list_width = 0;
+    let mut list_height = 0;
+    getmaxyx(list_window, &mut list_height, &mut list_width);
+    curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
 
-    let mut ch;
-    let mut ret: bool = false;
+    let mut selected = current;
+    let mut top = 0; // First buffer shown
+    let mut ret = None;
     loop {
-        ch = wget_wch(window);
-        getyx(window, &mut cur_y, &mut cur_x); // Get current cursor location
-        match ch {
-            Some(WchResult::Char(char_code)) => {
-                let c = char::from_u32(char_code as u32).expect("Invalid char");
-                match c {
-                    '\u{0003}' => {
-                        // Ctrl-C
-                        break;
-                    },
-                    '\u{007F}' => {
-                        // Backspace
+        // Scroll so that the selection is visible
+        let rows = max(list_height, 1) as usize;
+        if selected < top {
+            top = selected;
+        } else if selected >= top + rows {
+            top = selected + 1 - rows;
+        }
 
-                        // Check if can't delete further
-                        if cur_x == left_limit {
-                            beep();
-                            continue;
-                        }
+        werase(list_window);
+        for (i, buffer) in buffers.iter().enumerate().skip(top).take(rows) {
+            let modified = if buffer.editor.save_flag { "" } else { "  [Modified]" };
+            let line = format!(" {:>2}  {}{}", i + 1, buffer.name(), modified);
+            if i == selected {
+                wattron(list_window, A_REVERSE);
+            }
+            mvwaddstr(list_window, (i - top) as i32, 0, &line.chars().take(list_width as usize).collect::<String>()).unwrap();
+            wattroff(list_window, A_REVERSE);
+        }
+        wrefresh(list_window);
+
+        match wget_wch(list_window) {
+            Some(WchResult::KeyCode(KEY_UP)) if selected > 0 => { selected -= 1; },
+            Some(WchResult::KeyCode(KEY_DOWN)) if selected + 1 < buffers.len() => { selected += 1; },
+            Some(WchResult::Char(char_code)) => match char::from_u32(char_code) {
+                Some('\r') => {
+                    ret = Some(selected);
+                    break;
+                },
+                Some('\u{0003}') => { break; }, // Ctrl-C
+                _ => { beep(); }
+            },
+            _ => { beep(); }
+        }
+    }
 
-                        // We are essentially replacing the characters with spaces
-                        wmove(window, cur_y, cur_x - 1);
-                        wdelch(window);
-                        winsch(window, ' ' as chtype);
-                        replace_buffer.pop();
-                    },
-                    '\r' => {
-                        // Enter
-                        return Some(escape_regex(replace_buffer)?);
-                    },
-                    '\u{0001}'..='\u{001F}' => {
-                        beep();
-                    },
-                    _ => {
-                        if cur_x == right_limit {
-                            beep();
-                            continue;
-                        }
+    browser::close_window(list_window, editor_window);
+    curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
+    ret
+}
 
-                        waddch(window, c as chtype);
-                        replace_buffer.push(c);
+fn replace_confirm_loop(window: WINDOW, editor_window: WINDOW, editor: &mut gapnc::GapEditor, query: &search::SearchQuery, replace_string: String) {
+    // Handle UI sequence for replacing the search hits one by one
+    // All the accepted replacements are pushed to the history as one
+    // action group, so a single undo reverts the whole session
+
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(window, &mut max_y, &mut max_x);
+
+    let ctrl_string = "[Y] Yes\t[N] No\t[A] All\t[^C] Cancel".to_string();
+    let ctrl_string_len = ctrl_string.len();
+    let prompt_string = "Replace this instance?".to_string();
+    let prompt_string_len = prompt_string.len();
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
+    wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+    mvwaddstr(window, 0, 0, &(prompt_string + &" ".repeat((max_x as usize).saturating_sub(prompt_string_len)))).unwrap();
+    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+    wrefresh(window);
+
+    let mut action_groups = Vec::<undo::ActionGroup>::new();
+    // Start at the cursor (the hit the search stopped on) and wrap around
+    'hits: while let Some((l, r)) = editor.pop_search_hit_from(editor.pos()) {
+        // Show the hit as selected
+        if r > l {
+            editor.select_range(l, r - 1);
+        }
+        werase(editor_window);
+        editor.display_at_frame_cursor();
+        editor.move_cursor_to();
+        wrefresh(editor_window);
+
+        loop {
+            match wget_wch(window) {
+                Some(WchResult::Char(char_code)) => {
+                    let c = char::from_u32(char_code as u32).expect("Invalid char");
+                    match c {
+                        'y' | 'Y' => {
+                            action_groups.extend(editor.replace_search_hit(query, (l, r), &replace_string));
+                            break;
+                        },
+                        'n' | 'N' => {
+                            editor.deselect_marks();
+                            break;
+                        },
+                        'a' | 'A' => {
+                            // Replace this hit and all the ones after it
+                            action_groups.extend(editor.replace_search_hit(query, (l, r), &replace_string));
+                            action_groups.extend(editor.replace_all(query, replace_string.clone()));
+                            break 'hits;
+                        },
+                        'c' | 'C' | '\u{0003}' => {
+                            // Ctrl-C
+                            editor.deselect_marks();
+                            break 'hits;
+                        },
+                        _ => { beep(); }
                     }
+                },
+                _ => { beep(); }
+            }
+        }
+    }
+
+    if !action_groups.is_empty() {
+        editor.push_history(undo::merge_action_groups(action_groups));
+    }
+}
+
+fn repeat_search(window: WINDOW, editor: &mut gapnc::GapEditor, last_query: &Option<search::SearchQuery>, forward: bool) {
+    // Moves to the next (or previous) search hit, and shows which one it is
+    // If the hits were cleared (e.g. by undo), the last query is run again first
+
+    if !editor.has_search_hits() {
+        match last_query {
+            Some(query) => {
+                if let Err(e) = editor.search_all(query) {
+                    show_error(window, &format!("Invalid regex: {}", config::regex_error_reason(&e)));
+                    return;
                 }
             },
-            _ => {break;}
+            None => {
+                beep();
+                show_message(window, "No current search");
+                return;
+            }
+        }
+    }
+
+    let hit = if forward { editor.next_search_hit() } else { editor.prev_search_hit() };
+    match hit {
+        Some((1, 1, _)) => show_message(window, "This is the only match"),
+        Some((k, n, true)) => show_message(window, &format!("Match {} of {} (search wrapped)", k, n)),
+        Some((k, n, false)) => show_message(window, &format!("Match {} of {}", k, n)),
+        None => {
+            beep();
+            show_message(window, "No matches");
         }
-        wrefresh(window);
     }
-    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
-    None
 }
 
 fn clipboard_select_loop(window: WINDOW, editor: &gapnc::GapEditor) -> Option<usize>{
@@ -546,9 +970,9 @@ fn clipboard_select_loop(window: WINDOW, editor: &gapnc::GapEditor) -> Option<us
     let ctrl_string_len = ctrl_string.len();
     let clipboard_select_string = "Clipboard: ".to_string();
     let clipboard_select_string_len = clipboard_select_string.len();
-    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat(max_x as usize - ctrl_string_len))).unwrap();
+    mvwaddstr(window, 1, 0, &(ctrl_string + &" ".repeat((max_x as usize).saturating_sub(ctrl_string_len)))).unwrap();
     wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
-    mvwaddstr(window, 0, 0, &(clipboard_select_string + &" ".repeat(max_x as usize - clipboard_select_string_len))).unwrap();
+    mvwaddstr(window, 0, 0, &(clipboard_select_string + &" ".repeat((max_x as usize).saturating_sub(clipboard_select_string_len)))).unwrap();
     wmove(window, 0, clipboard_select_string_len as i32); // This is the position right after the clipboard select string
 
     // Get the initial clipboard string
@@ -642,6 +1066,61 @@ fn clipboard_select_loop(window: WINDOW, editor: &gapnc::GapEditor) -> Option<us
     None
 }
 
+fn show_message(window: WINDOW, message: &str) {
+    // Shows a message in the top row of the control bar, until the bar is redrawn
+    draw_message(window, message, colors::CP_HIGHLIGHT);
+}
+
+fn show_error(window: WINDOW, message: &str) {
+    // Shows an error the same way as a message, in the error color
+    draw_message(window, message, colors::CP_ERROR);
+}
+
+fn draw_message(window: WINDOW, message: &str, color: i16) {
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(window, &mut max_y, &mut max_x);
+
+    // Long messages are cut off rather than running into the next row
+    let message: String = format!("[ {} ]", message).chars().take(max_x as usize).collect();
+    let width = max(max_x as usize, message.len());
+    wattron(window, COLOR_PAIR(color));
+    mvwaddstr(window, 0, 0, &pad(message, width)).unwrap();
+    wattroff(window, COLOR_PAIR(color));
+    wrefresh(window);
+}
+
+fn draw_status_line(window: WINDOW, buffer: &Buffer) {
+    // Shows the buffer's name, whether it's modified, the cursor's position and the
+    // file's format in the top row of the control bar, where messages and prompts go otherwise
+
+    let mut max_x = 0;
+    let mut max_y = 0;
+    getmaxyx(window, &mut max_y, &mut max_x);
+    let width = max_x as usize;
+
+    let editor = &buffer.editor;
+    let (line, col) = editor.cursor_line_col();
+    let name = if editor.save_flag { buffer.name() } else { format!("{} [Modified]", buffer.name()) };
+    let position = format!("Line {}/{}, Col {}   {}   {} ", line, editor.line_count(), col, buffer.encoding.name(), buffer.line_ending.name());
+
+    // The name is cut from the front if there isn't room for it
+    let name_room = width.saturating_sub(position.len() + 2);
+    let mut name_chars: Vec<char> = name.chars().collect();
+    if prompt::str_width(&name) > name_room {
+        while !name_chars.is_empty() && prompt::str_width(&name_chars.iter().collect::<String>()) + 3 > name_room {
+            name_chars.remove(0);
+        }
+        name_chars.splice(0..0, "...".chars());
+    }
+    let name: String = name_chars.into_iter().collect();
+    let gap = width.saturating_sub(prompt::str_width(&name) + 1 + position.len());
+
+    wattron(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+    mvwaddstr(window, 0, 0, &format!(" {}{}{}", name, " ".repeat(gap), position)).unwrap();
+    wattroff(window, COLOR_PAIR(colors::CP_HIGHLIGHT));
+}
+
 fn refresh_all_windows(windows: &Vec<WINDOW>) {
     // Refreshes all windows
     for window in windows.iter() {
@@ -704,22 +1183,14 @@ fn pad(string: String, length: usize) -> String {
     }
 }
 
-fn escape_regex(string: String) -> Option<String> {
-    // Escapes all characters in string
-    let re = Regex::new(r"(?<m>[.*+?^${}()|\[\]\\])").unwrap();
-    Some(re.replace_all(&string, r"\${m}").to_string())
-}
-
 // Main loop
 fn main() {
-    //setlocale(constants::LcCategory::all, ""); // We need this to display weird unicode characters
-    //setlocale(constants::LcCategory::all, "en_US.UTF-8");
-    //setlocale(LcCategory::all, "en_US.UTF-8");
-    //setlocale(LcCategory::all, "");
+    setlocale(LcCategory::all, "").unwrap(); // We need this to display weird unicode characters
     initscr();
     raw();
     noecho();
     nonl();
+    set_escdelay(keymap::ESC_DELAY); // ncurses waits a whole second by default, which a lone Esc would have to sit through
 
     // Initialize colors
     colors::init_colors();
@@ -740,24 +1211,31 @@ fn main() {
 
     // Initialize editor
     let args: Vec<_> = env::args().collect();
-    let mut path;
-    let mut editor;
+    let mut buffers = Vec::<Buffer>::new();
+    let mut current = 0; // Buffer being edited
     /*
     if args.len() != 2 {
         panic!("Requires filepath argument");
     }
     */
     if args.len() == 1 {
-        /*
-        path = String::new();
-        editor = nc::Editor::blank(editor_window);
-        */
-        panic!("Empty Editor not implemented!");
-    } else if args.len() == 2 {
-        path = args[1].to_string();
-        editor = open_file(editor_window, &path);
-    } else {
-        panic!("More than 1 argument provided!");
+        // Unnamed scratch buffer, which asks for a name when it's saved
+        buffers.push(Buffer::empty(editor_window, None, false));
+    }
+    for path in args.iter().skip(1) {
+        let buffer = match open_file(editor_window, path) {
+            Ok(buffer) => buffer,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // The file gets created when the buffer is first saved
+                Buffer::empty(editor_window, Some(path.to_string()), true)
+            },
+            Err(e) => {
+                endwin();
+                eprintln!("Unable to open {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        buffers.push(buffer);
     }
 
     // Manually adding regex syntax highlighting rules
@@ -770,44 +1248,97 @@ fn main() {
     */
     //editor.set_highlight_rules(syntax_highlighting_demo::build_highlighting_rules());
 
+    let mut config_error = None; // The first one is shown, the others are likely the same
+    for buffer in buffers.iter_mut() {
+        if let Err(e) = apply_config(&mut buffer.editor, buffer.path.as_deref()) {
+            config_error.get_or_insert(e);
+        }
+    }
+
     // Initialize rest
-    draw_control_bar(ctrl_window);
+    let mut keymap = keymap::default_keymap();
+    if let Err(e) = load_keymap(&mut keymap) {
+        config_error.get_or_insert(e);
+    }
+    draw_control_bar(ctrl_window, &keymap);
+    draw_status_line(ctrl_window, &buffers[current]);
+    let editor = &mut buffers[current].editor;
     editor.display_at_frame_cursor();
     //editor.move_cursor_to(editor_window);
     editor.move_cursor_to();
     //wrefresh(editor_window);
     refresh_all_windows(&windows);
 
-    let mut ch = wget_wch(editor_window);
+    let mut last_query: Option<search::SearchQuery> = None; // For find next/previous
+    let mut message_shown = false; // Cleared from the control bar on the next key
+    // Earlier inputs of each prompt, recalled with Up/Down
+    let mut search_history = prompt::History::new();
+    let mut replace_history = prompt::History::new();
+    let mut filename_history = prompt::History::new();
+    let mut line_history = prompt::History::new();
+
+    if let Some(e) = config_error {
+        show_error(ctrl_window, &format!("Error in config: {}", e));
+        message_shown = true;
+    } else if let Some(warning) = buffers[current].encoding_warning() {
+        show_error(ctrl_window, &warning);
+        message_shown = true;
+    } else if buffers[current].new_file {
+        show_message(ctrl_window, "New File");
+        message_shown = true;
+    } else if buffers.len() > 1 {
+        let hints = keymap.hints(&[
+            (keymap::Command::NextBuffer, "Next"),
+            (keymap::Command::PreviousBuffer, "Previous"),
+            (keymap::Command::BufferList, "List")
+        ], " ");
+        show_message(ctrl_window, &format!("{} buffers open ({})", buffers.len(), hints));
+        message_shown = true;
+    }
+
+    let mut key = keymap::read_key(editor_window);
     while true {
-        match ch {
+        if message_shown {
+            // Any key clears the message
+            draw_control_bar(ctrl_window, &keymap);
+            wrefresh(ctrl_window);
+            message_shown = false;
+        }
+        let mut command = None; // Run once the current buffer is let go of
+        let buffers_len = buffers.len();
+        let Buffer { editor, path, new_file, encoding, line_ending } = &mut buffers[current];
+        let key_pressed = match key {
+            Some(key_pressed) => key_pressed,
+            None => { break; }
+        };
+        match keymap.lookup(key_pressed) {
             // Arrow keys
-            Some(WchResult::KeyCode(KEY_DOWN)) => {
+            Some(keymap::Command::Down) => {
                 if editor.is_shift_selected() {
                     editor.deselect_marks();
                 }
                 editor.scroll_down();
             },
-            Some(WchResult::KeyCode(KEY_UP)) => {
+            Some(keymap::Command::Up) => {
                 if editor.is_shift_selected() {
                     editor.deselect_marks();
                 }
                 editor.scroll_up();
             },
-            Some(WchResult::KeyCode(KEY_RIGHT)) => {
+            Some(keymap::Command::Right) => {
                 if editor.is_shift_selected() {
                     editor.deselect_marks();
                 }
                 editor.scroll_right();
             },
-            Some(WchResult::KeyCode(KEY_LEFT)) => {
+            Some(keymap::Command::Left) => {
                 if editor.is_shift_selected() {
                     editor.deselect_marks();
                 }
                 editor.scroll_left();
             },
             // Shift Arrow Keys
-            Some(WchResult::KeyCode(KEY_SDOWN)) => {
+            Some(keymap::Command::SelectDown) => {
                 if !editor.is_shift_selected() {
                     editor.set_mark();
                     editor.set_select_shift();
@@ -815,21 +1346,21 @@ fn main() {
                 editor.scroll_down();
                 //panic!("{} {} {} {}", KEY_SDOWN, KEY_SUP, KEY_SRIGHT, KEY_SLEFT);
             },
-            Some(WchResult::KeyCode(KEY_SUP)) => {
+            Some(keymap::Command::SelectUp) => {
                 if !editor.is_shift_selected() {
                     editor.set_mark();
                     editor.set_select_shift();
                 }
                 editor.scroll_up();
             },
-            Some(WchResult::KeyCode(KEY_SRIGHT)) => {
+            Some(keymap::Command::SelectRight) => {
                 if !editor.is_shift_selected() {
                     editor.set_mark();
                     editor.set_select_shift();
                 }
                 editor.scroll_right();
             },
-            Some(WchResult::KeyCode(KEY_SLEFT)) => {
+            Some(keymap::Command::SelectLeft) => {
                 if !editor.is_shift_selected() {
                     editor.set_mark();
                     editor.set_select_shift();
@@ -837,145 +1368,310 @@ fn main() {
                 editor.scroll_left();
             },
             // Control Arrow Keys
-            Some(WchResult::KeyCode(KEY_CDOWN)) => {
+            Some(keymap::Command::FastDown) => {
                 editor.fast_down();
             },
-            Some(WchResult::KeyCode(KEY_CUP)) => {
+            Some(keymap::Command::FastUp) => {
                 editor.fast_up();
             },
-            Some(WchResult::KeyCode(KEY_CRIGHT)) => {
+            Some(keymap::Command::FastRight) => {
                 //editor.next_word();
                 editor.fast_right();
             },
-            Some(WchResult::KeyCode(KEY_CLEFT)) => {
+            Some(keymap::Command::FastLeft) => {
                 //editor.prev_word();
                 editor.fast_left();
             },
-            // Unrecognized keycode
-            Some(WchResult::KeyCode(code)) => {
-                panic!("Got keycode: {:?}", code);
-            }
-            // Actual characters + Ctrl keys
-            Some(WchResult::Char(char_code)) => {
-                // Typed some character
-                let c = char::from_u32(char_code as u32).expect("Invalid char");
-                match c {
-                    '\r' => {
-                        // Enter
-                        editor.newline_h();
-                    },
-                    '\t' => {
-                        editor.tab_h();
-                    },
-                    '\u{007F}' => {
-                        // Handle backspaces separately
-                        editor.backspace_h();
-                        //editor.backspace(false);
-                    },
-                    '\u{0001}' => {
-                        // Ctrl-A -> undo
-                        editor.undo();
-                    },
-                    '\u{001A}' => {
-                        // Ctrl-Z -> redo
-                        editor.redo();
-                    },
-                    '\u{0018}' => {
-                        // Ctrl-X -> break
-                        //TODO Implement
-                        if editor.save_flag {
-                            break;
-                        } else if exit_loop(ctrl_window, &editor, &path) {
-                            break;
-                        } else {
-                            draw_control_bar(ctrl_window);
-                            wrefresh(ctrl_window);
-                        }
-                    },
-                    '\u{000F}' => {
-                        // Ctrl-O -> save loop
-                        if save_loop(ctrl_window, &editor, &path) {
-                            editor.unset_save();
-                        }
-                        draw_control_bar(ctrl_window);
-                        wrefresh(ctrl_window);
-                    },
-                    '\u{0010}' => {
-                        // Ctrl-P -> clipboard
-                        match clipboard_select_loop(ctrl_window, &editor) {
-                            Some(new_clipboard_cursor) => { editor.set_clipboard_cursor(new_clipboard_cursor) },
-                            None => {}
+            // Page and line navigation
+            Some(keymap::Command::PageDown) => {
+                if editor.is_shift_selected() {
+                    editor.deselect_marks();
+                }
+                editor.page_down();
+            },
+            Some(keymap::Command::PageUp) => {
+                if editor.is_shift_selected() {
+                    editor.deselect_marks();
+                }
+                editor.page_up();
+            },
+            Some(keymap::Command::Home) => {
+                if editor.is_shift_selected() {
+                    editor.deselect_marks();
+                }
+                editor.home();
+            },
+            Some(keymap::Command::End) => {
+                if editor.is_shift_selected() {
+                    editor.deselect_marks();
+                }
+                editor.end();
+            },
+            Some(keymap::Command::BufferStart) => {
+                if editor.is_shift_selected() {
+                    editor.deselect_marks();
+                }
+                editor.buffer_start();
+            },
+            Some(keymap::Command::BufferEnd) => {
+                if editor.is_shift_selected() {
+                    editor.deselect_marks();
+                }
+                editor.buffer_end();
+            },
+            Some(keymap::Command::Newline) => {
+                editor.newline_h();
+            },
+            Some(keymap::Command::Tab) => {
+                editor.tab_h();
+            },
+            Some(keymap::Command::Backspace) => {
+                editor.backspace_h();
+                //editor.backspace(false);
+            },
+            Some(keymap::Command::Delete) => {
+                editor.delete_h();
+            },
+            Some(keymap::Command::Undo) => {
+                editor.undo();
+            },
+            Some(keymap::Command::Redo) => {
+                editor.redo();
+            },
+            Some(keymap::Command::Quit) => {
+                // Break (once every buffer is saved or discarded)
+                command = Some(BufferCommand::Quit);
+            },
+            Some(keymap::Command::Save) => {
+                let saved = save_loop(ctrl_window, editor_window, editor, path.as_deref(), *encoding, &mut filename_history, true);
+                draw_control_bar(ctrl_window, &keymap);
+                wrefresh(ctrl_window);
+                match saved {
+                    Ok(Some(target)) => {
+                        if let Some(message) = record_save(editor, path, new_file, line_ending, target) {
+                            show_message(ctrl_window, &message);
+                            message_shown = true;
                         }
-                        draw_control_bar(ctrl_window);
-                        wrefresh(ctrl_window);
                     },
-                    '\u{000A}' => {
-                        // Ctrl-J -> copy
-                        editor.copy();
+                    Ok(None) => {},
+                    Err(message) => {
+                        // The buffer stays modified
+                        show_error(ctrl_window, &message);
+                        message_shown = true;
+                    }
+                }
+            },
+            Some(keymap::Command::Open) => {
+                // Open file (in a new buffer)
+                if let Some(new_path) = filename_loop(ctrl_window, editor_window, &mut prompt::Prompt::new("File to Open: ", ""), "[Enter] Open",
+                                                      &mut filename_history, &mut |_, _| false) {
+                    command = Some(BufferCommand::Open(new_path));
+                }
+                draw_control_bar(ctrl_window, &keymap);
+                wrefresh(ctrl_window);
+            },
+            Some(keymap::Command::Clipboard) => {
+                match clipboard_select_loop(ctrl_window, editor) {
+                    Some(new_clipboard_cursor) => { editor.set_clipboard_cursor(new_clipboard_cursor) },
+                    None => {}
+                }
+                draw_control_bar(ctrl_window, &keymap);
+                wrefresh(ctrl_window);
+            },
+            Some(keymap::Command::Copy) => {
+                editor.copy();
+            },
+            Some(keymap::Command::Cut) => {
+                editor.cut_h();
+            },
+            Some(keymap::Command::SetMark) => {
+                editor.set_mark();
+            },
+            Some(keymap::Command::Paste) => {
+                //editor.paste();
+                editor.paste_h();
+            },
+            Some(keymap::Command::Find) => {
+                let found = match search_loop(ctrl_window, editor_window, editor, &mut search_history, &mut replace_history) {
+                    Some((query, None)) => {
+                        //panic!("Search: {:?}", search_string);
+                        editor.clear_search();
+                        //editor.find_all(search_string, editor.pos());
+                        editor.find_all(&query);
+                        last_query = Some(query);
+                        true
                     },
-                    '\u{000B}' => {
-                        // Ctrl-K -> cut
-                        editor.cut_h();
+                    Some((query, Some(replace_string))) => {
+                        editor.clear_search();
+                        editor.find_all(&query);
+                        replace_confirm_loop(ctrl_window, editor_window, editor, &query, replace_string);
+                        last_query = Some(query);
+                        false
                     },
-                    '\u{000C}' => {
-                        // Ctrl-L -> Set mark
-                        editor.set_mark();
+                    None => false
+                };
+                draw_control_bar(ctrl_window, &keymap);
+                wrefresh(ctrl_window);
+                if found {
+                    match editor.current_search_hit() {
+                        Some((k, n)) => show_message(ctrl_window, &format!("Match {} of {}", k, n)),
+                        None => show_message(ctrl_window, "No matches")
                     }
-                    '\u{0015}' => {
-                        // Ctrl-U -> paste
-                        //editor.paste();
-                        //TODO Implement
-                        //break;
-                        editor.paste_h();
+                    message_shown = true;
+                }
+            },
+            Some(keymap::Command::FindNext) => {
+                repeat_search(ctrl_window, editor, &last_query, true);
+                message_shown = true;
+            },
+            Some(keymap::Command::FindPrevious) => {
+                repeat_search(ctrl_window, editor, &last_query, false);
+                message_shown = true;
+            },
+            Some(keymap::Command::NextBuffer) => {
+                command = Some(BufferCommand::Switch((current + 1) % buffers_len));
+            },
+            Some(keymap::Command::PreviousBuffer) => {
+                command = Some(BufferCommand::Switch((current + buffers_len - 1) % buffers_len));
+            },
+            Some(keymap::Command::BufferList) => {
+                command = Some(BufferCommand::Pick);
+            },
+            Some(keymap::Command::GoToLine) => {
+                let line_count = editor.line_count();
+                let line = go_to_line_loop(ctrl_window, editor, &mut line_history);
+                draw_control_bar(ctrl_window, &keymap);
+                wrefresh(ctrl_window);
+                match line {
+                    Some(n) if (1..=line_count).contains(&n) => { editor.go_to_line(n - 1); },
+                    Some(n) => {
+                        beep();
+                        show_error(ctrl_window, &format!("There is no line {} (the last is {})", n, line_count));
+                        message_shown = true;
                     },
-                    '\u{0017}' => {
-                        // Ctrl-W -> Find
-                        match search_loop(ctrl_window, &editor) {
-                            Some((search_string, None)) => {
-                                //panic!("Search: {:?}", search_string);
-                                editor.clear_search();
-                                //editor.find_all(search_string, editor.pos());
-                                editor.find_all(search_string, 0);
-                            },
-                            Some((search_string, Some(replace_string))) => {
-                                editor.clear_search();
-                                editor.find_all(search_string, 0);
-                                editor.replace_all_h(replace_string);
-                            },
-                            None => {}
+                    None => { beep(); }
+                }
+            },
+            Some(keymap::Command::LineNumbers) => {
+                let (line_numbers, name) = match editor.line_numbers() {
+                    gapnc::LineNumbers::Off => (gapnc::LineNumbers::Absolute, "Line numbers"),
+                    gapnc::LineNumbers::Absolute => (gapnc::LineNumbers::Relative, "Relative line numbers"),
+                    gapnc::LineNumbers::Relative => (gapnc::LineNumbers::Hybrid, "Hybrid line numbers"),
+                    gapnc::LineNumbers::Hybrid => (gapnc::LineNumbers::Off, "No line numbers")
+                };
+                editor.set_line_numbers(line_numbers);
+                show_message(ctrl_window, name);
+                message_shown = true;
+            },
+            None => match key_pressed {
+                keymap::Key::Char(c) if !c.is_control() => {
+                    // Typed some character
+                    editor.type_character_h(c);
+                },
+                keymap::Key::Code(KEY_RESIZE) => {
+                    // Handled below, with resizes that happened during prompts
+                },
+                _ => { beep(); } // Unbound key
+            }
+        }
+
+        match command {
+            Some(BufferCommand::Switch(index)) => {
+                current = index;
+                show_message(ctrl_window, &buffers[current].switch_message());
+                message_shown = true;
+            },
+            Some(BufferCommand::Pick) => {
+                let picked = buffer_list_loop(ctrl_window, editor_window, &buffers, current);
+                draw_control_bar(ctrl_window, &keymap);
+                if let Some(index) = picked {
+                    current = index;
+                    show_message(ctrl_window, &buffers[current].switch_message());
+                    message_shown = true;
+                }
+                wrefresh(ctrl_window);
+            },
+            Some(BufferCommand::Open(new_path)) => {
+                match buffers.iter().position(|buffer| buffer.path.as_deref().is_some_and(|path| same_file(path, &new_path))) {
+                    Some(index) => { current = index; }, // Already open
+                    None => match open_file(editor_window, &new_path) {
+                        Ok(mut buffer) => {
+                            if let Err(e) = apply_config(&mut buffer.editor, Some(&new_path)) {
+                                show_error(ctrl_window, &format!("Error in config: {}", e));
+                                message_shown = true;
+                            } else if let Some(warning) = buffer.encoding_warning() {
+                                show_error(ctrl_window, &warning);
+                                message_shown = true;
+                            }
+                            buffers.push(buffer);
+                            current = buffers.len() - 1;
+                        },
+                        Err(e) => {
+                            show_error(ctrl_window, &format!("Can't open {}: {}", new_path, e));
+                            message_shown = true;
                         }
-                        draw_control_bar(ctrl_window);
-                        wrefresh(ctrl_window);
-                    },
-                    '\u{001F}' => {
-                        // Ctrl-/ -> Go to line
-                        match go_to_line_loop(ctrl_window, &editor) {
-                            Some(n) => { editor.go_to_line(n); },
-                            None => { beep(); }
+                    }
+                }
+            },
+            Some(BufferCommand::Quit) => {
+                // Go through the modified buffers, starting with this one
+                let mut quit = true;
+                for index in (current..buffers.len()).chain(0..current) {
+                    if buffers[index].editor.save_flag {
+                        continue;
+                    }
+                    current = index;
+                    let buffer = &mut buffers[current];
+                    werase(editor_window);
+                    buffer.editor.display_at_frame_cursor();
+                    buffer.editor.move_cursor_to();
+                    wrefresh(editor_window);
+                    match exit_loop(ctrl_window, editor_window, buffer, &mut filename_history) {
+                        Ok(true) => {},
+                        Ok(false) => {
+                            quit = false;
+                            draw_control_bar(ctrl_window, &keymap);
+                            wrefresh(ctrl_window);
+                            break;
+                        },
+                        Err(message) => {
+                            quit = false;
+                            draw_control_bar(ctrl_window, &keymap);
+                            show_error(ctrl_window, &message);
+                            message_shown = true;
+                            break;
                         }
-                        draw_control_bar(ctrl_window);
-                        wrefresh(ctrl_window);
-                    },
-                    '\u{0001}'..='\u{001F}' => { // All other control keys
-                        beep();
-                    },
-                    _ => {
-                        editor.type_character_h(c);
-                        //break;
                     }
                 }
+                if quit {
+                    break;
+                }
             },
-            _ => {
-                break;
+            None => {}
+        }
+
+        if resize_windows(editor_window, ctrl_window) {
+            for buffer in buffers.iter_mut() {
+                buffer.editor.resize();
             }
+            draw_control_bar(ctrl_window, &keymap);
+            wrefresh(ctrl_window);
+            message_shown = false; // Redrawing the bar cleared it
         }
+
+        if !message_shown {
+            draw_status_line(ctrl_window, &buffers[current]);
+            wrefresh(ctrl_window);
+        }
+
+        let editor = &mut buffers[current].editor;
         werase(editor_window);
         editor.display_at_frame_cursor();
         //editor.move_cursor_to(editor_window);
         editor.move_cursor_to();
         wrefresh(editor_window);
         //refresh_all_windows(&windows);
-        ch = wget_wch(editor_window);
+        key = keymap::read_key(editor_window);
     }
     endwin();
 }
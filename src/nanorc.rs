@@ -0,0 +1,396 @@
+// Importer for nano's syntax definition files (.nanorc)
+// Only the syntax highlighting commands are understood (syntax, header, magic,
+// color and icolor); everything else is skipped
+
+extern crate ncurses;
+use ncurses::*;
+use std::fs;
+use std::path::Path;
+use regex::Regex;
+use crate::colors;
+use crate::config::{self, ConfigError, Target};
+use crate::syntax_highlighting::SyntaxHighlight;
+
+pub struct NanorcSyntax {
+    pub name: String,
+    file_regexes: Vec<Regex>,
+    headers: Vec<Regex>,
+    magics: Vec<Regex>,
+    rules: Vec<SyntaxHighlight> // In nanorc order, i.e. later rules paint over earlier ones
+}
+
+impl NanorcSyntax {
+    fn new(name: String) -> NanorcSyntax {
+        NanorcSyntax { name, file_regexes: Vec::new(), headers: Vec::new(), magics: Vec::new(), rules: Vec::new() }
+    }
+
+    pub fn matches_file(&self, target: &Target) -> bool {
+        // Checks the file name regexes against the path
+        match &target.path {
+            Some(path) => {
+                let path = path.to_string_lossy();
+                self.file_regexes.iter().any(|re| re.is_match(&path))
+            },
+            None => false
+        }
+    }
+
+    pub fn matches_header(&self, target: &Target) -> bool {
+        // Checks the header regexes against the first line
+        self.headers.iter().any(|re| re.is_match(&target.first_line))
+    }
+
+    pub fn matches_magic(&self, target: &Target) -> bool {
+        // nano matches these against the output of libmagic; we don't have that,
+        // so the best we can do is check them against the first line as well
+        self.magics.iter().any(|re| re.is_match(&target.first_line))
+    }
+
+    pub fn into_rules(self) -> Vec<SyntaxHighlight> {
+        // Gets the rules in HighlightRules order (see HighlightRules::new). In nano the last matching
+        // rule wins, whereas in HighlightRules the first one does, so the
+        // rules are reversed
+        self.rules.into_iter().rev().collect()
+    }
+}
+
+pub fn find_syntax(syntaxes: Vec<NanorcSyntax>, target: &Target) -> Option<NanorcSyntax> {
+    // Picks the syntax for the target the same way nano does: file name first,
+    // then header lines, then magic, and finally a syntax called "default"
+    let checks: [fn(&NanorcSyntax, &Target) -> bool; 4] = [
+        NanorcSyntax::matches_file,
+        NanorcSyntax::matches_header,
+        NanorcSyntax::matches_magic,
+        |syntax, _| syntax.name == "default"
+    ];
+    let index = checks.iter().find_map(|check| syntaxes.iter().position(|syntax| check(syntax, target)))?;
+    syntaxes.into_iter().nth(index)
+}
+
+pub fn load_nanorc(path: &Path, skipped: &mut Vec<ConfigError>) -> Result<Vec<NanorcSyntax>, ConfigError> {
+    // Reads and parses a nanorc file
+    // Bad rules are left out, and their errors added to skipped
+    let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    let mut errors = Vec::new();
+    let syntaxes = parse_nanorc(&contents, &mut errors);
+    skipped.extend(errors.into_iter().map(|e| ConfigError::Eval(format!("{}: {}", path.display(), e))));
+    Ok(syntaxes)
+}
+
+pub fn parse_nanorc(contents: &str, skipped: &mut Vec<ConfigError>) -> Vec<NanorcSyntax> {
+    // Parses the syntax definitions in a nanorc file
+    // A bad line (e.g. a bad regex or an unknown color) only costs the rule
+    // (or file/header/magic pattern) it's in; its error goes into skipped
+    // and the rest of the file is still used
+    let mut syntaxes = Vec::<NanorcSyntax>::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let lineno = i + 1;
+        let args = split_args(line);
+        let (command, args) = match args.split_first() {
+            Some((command, args)) => (command.as_str(), args),
+            None => { continue; }
+        };
+        if command.starts_with('#') {
+            continue;
+        }
+
+        if command == "syntax" {
+            let (name, file_regexes) = match args.split_first() {
+                Some(split) => split,
+                None => {
+                    // Its rules still need somewhere to go, so they don't end up in the syntax before
+                    skipped.push(ConfigError::Syntax(lineno, "missing syntax name".to_string()));
+                    syntaxes.push(NanorcSyntax::new(String::new()));
+                    continue;
+                }
+            };
+            let mut syntax = NanorcSyntax::new(name.clone());
+            for file_regex in file_regexes {
+                syntax.file_regexes.extend(skip_bad(compile(file_regex, false, lineno), skipped));
+            }
+            syntaxes.push(syntax);
+            continue;
+        }
+
+        // Everything else belongs to the most recent syntax
+        let syntax = match syntaxes.last_mut() {
+            Some(syntax) => syntax,
+            None => {
+                if ["header", "magic", "color", "icolor"].contains(&command) {
+                    skipped.push(ConfigError::Syntax(lineno, format!("{} without a syntax", command)));
+                }
+                continue;
+            }
+        };
+        match command {
+            "header" => {
+                for header in args {
+                    syntax.headers.extend(skip_bad(compile(header, false, lineno), skipped));
+                }
+            },
+            "magic" => {
+                for magic in args {
+                    syntax.magics.extend(skip_bad(compile(magic, false, lineno), skipped));
+                }
+            },
+            "color" | "icolor" => {
+                let icase = command == "icolor";
+                let (spec, regexes) = match args.split_first() {
+                    Some(split) => split,
+                    None => {
+                        skipped.push(ConfigError::Syntax(lineno, format!("missing color for {}", command)));
+                        continue;
+                    }
+                };
+                let color = match parse_color_spec(spec) {
+                    Some(color) => color,
+                    None => {
+                        skipped.push(ConfigError::Syntax(lineno, format!("bad color {:?}", spec)));
+                        continue;
+                    }
+                };
+
+                if let Some(start) = regexes.first().and_then(|arg| arg.strip_prefix("start=")) {
+                    let end = match regexes.get(1).and_then(|arg| arg.strip_prefix("end=")) {
+                        Some(end) => end,
+                        None => {
+                            skipped.push(ConfigError::Syntax(lineno, "start= without end=".to_string()));
+                            continue;
+                        }
+                    };
+                    let start = skip_bad(compile(start, icase, lineno), skipped);
+                    let end = skip_bad(compile(end, icase, lineno), skipped);
+                    if let (Some(start), Some(end)) = (start, end) {
+                        syntax.rules.push(SyntaxHighlight::region(start, end, color));
+                    }
+                } else {
+                    for regex in regexes {
+                        if let Some(regex) = skip_bad(compile(regex, icase, lineno), skipped) {
+                            syntax.rules.push(SyntaxHighlight::new(regex, color));
+                        }
+                    }
+                }
+            },
+            _ => {} // comment, linter, formatter, tabgives, ...
+        }
+    }
+    syntaxes
+}
+
+fn split_args(line: &str) -> Vec<String> {
+    // Splits a nanorc line into arguments, removing quotes
+    // As in nano, a quoted argument only ends at a quote followed by
+    // whitespace (or the end of the line), so regexes can contain quotes
+    let chars: Vec<char> = line.chars().collect();
+    let mut args = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let mut arg = String::new();
+        let mut quoted = false;
+        while i < chars.len() {
+            let ch = chars[i];
+            if !quoted && ch.is_whitespace() {
+                break;
+            } else if ch == '"' && !quoted {
+                quoted = true;
+            } else if ch == '"' && quoted && chars.get(i + 1).is_none_or(|c| c.is_whitespace()) {
+                quoted = false;
+            } else {
+                arg.push(ch);
+            }
+            i += 1;
+        }
+        args.push(arg);
+    }
+    args
+}
+
+fn compile(pattern: &str, icase: bool, lineno: usize) -> Result<Regex, ConfigError> {
+    Regex::new(&translate_regex(pattern, icase)).map_err(|e| ConfigError::Syntax(lineno, format!("bad regex {:?}: {}", pattern, config::regex_error_reason(&e))))
+}
+
+fn skip_bad(result: Result<Regex, ConfigError>, skipped: &mut Vec<ConfigError>) -> Option<Regex> {
+    // Keeps a compiled regex, or sets its error aside
+    match result {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            skipped.push(e);
+            None
+        }
+    }
+}
+
+pub fn translate_regex(pattern: &str, icase: bool) -> String {
+    // Translates a POSIX extended regex (as used by nano) into regex crate syntax
+    // - \< and \> become \b{start} and \b{end}
+    // - Backslashes inside brackets are literal in POSIX, so they get escaped,
+    //   as do [, & and ~ (which the regex crate uses for nested classes and set operations)
+    // - POSIX classes like [:alpha:] are kept, since the regex crate understands them
+    // - A { that doesn't start a repetition is literal
+    // nano matches line by line, so ^ and $ match at line boundaries
+    let mut out = String::from(if icase { "(?mi)" } else { "(?m)" });
+    let chars: Vec<char> = pattern.chars().collect();
+    let repetition = Regex::new(r"^\{[0-9]+(,[0-9]*)?\}").unwrap();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                match chars.get(i + 1) {
+                    Some('<') => out.push_str(r"\b{start}"),
+                    Some('>') => out.push_str(r"\b{end}"),
+                    Some(c) => { out.push('\\'); out.push(*c); },
+                    None => out.push_str(r"\\")
+                }
+                i += 2;
+            },
+            '[' => {
+                out.push('[');
+                i += 1;
+                if chars.get(i) == Some(&'^') {
+                    out.push('^');
+                    i += 1;
+                }
+                if chars.get(i) == Some(&']') {
+                    // A ] right at the start is a literal
+                    out.push_str(r"\]");
+                    i += 1;
+                }
+                while i < chars.len() && chars[i] != ']' {
+                    match chars[i] {
+                        '[' if chars.get(i + 1) == Some(&':') => {
+                            // Copy the POSIX class through to the closing :]
+                            while i < chars.len() && !(chars[i] == ']' && chars[i - 1] == ':') {
+                                out.push(chars[i]);
+                                i += 1;
+                            }
+                            if i < chars.len() {
+                                out.push(']');
+                            }
+                        },
+                        '\\' | '[' | '&' | '~' => { out.push('\\'); out.push(chars[i]); },
+                        c => out.push(c)
+                    }
+                    i += 1;
+                }
+                out.push(']');
+                i += 1;
+            },
+            '{' => {
+                // Only a well-formed {n}, {n,} or {n,m} is a repetition
+                let rest: String = chars[i..].iter().collect();
+                if repetition.is_match(&rest) {
+                    out.push('{');
+                } else {
+                    out.push_str(r"\{");
+                }
+                i += 1;
+            },
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn parse_color_spec(spec: &str) -> Option<u64> {
+    // Parses [attributes,][fg][,bg], e.g. "brightred", "bold,italic,green,black" or ",blue"
+    let mut attrs: u64 = 0;
+    let mut parts: Vec<&str> = spec.split(',').collect();
+    while parts.len() > 1 {
+        let attr = match parts[0] {
+            "bold" => A_BOLD,
+            "italic" => A_NORMAL, // The ncurses bindings don't expose A_ITALIC, so italic is dropped
+            "underline" => A_UNDERLINE,
+            "reverse" => A_REVERSE,
+            _ => { break; }
+        };
+        attrs |= attr;
+        parts.remove(0);
+    }
+    if parts.len() > 2 {
+        return None;
+    }
+
+    let (fg, bold) = match parts[0] {
+        "" => (-1, false),
+        name => parse_color_name(name)?
+    };
+    let bg = match parts.get(1) {
+        Some(name) if !name.is_empty() => parse_color_name(name)?.0,
+        _ => -1
+    };
+    if bold {
+        attrs |= A_BOLD;
+    }
+    Some(COLOR_PAIR(colors::color_pair(fg, bg)) | attrs)
+}
+
+fn parse_color_name(name: &str) -> Option<(i16, bool)> {
+    // Returns the color and whether it was bright/light (which we show as bold)
+    let (base, bright) = match name.strip_prefix("bright").or_else(|| name.strip_prefix("light")) {
+        Some(base) => (base, true),
+        None => (name, false)
+    };
+    let color = match base {
+        "normal" => -1,
+        "black" => COLOR_BLACK,
+        "red" => COLOR_RED,
+        "green" => COLOR_GREEN,
+        "yellow" => COLOR_YELLOW,
+        "blue" => COLOR_BLUE,
+        "magenta" => COLOR_MAGENTA,
+        "cyan" => COLOR_CYAN,
+        "white" => COLOR_WHITE,
+        _ => { return None; }
+    };
+    Some((color, bright))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(skipped: &[ConfigError]) -> Vec<usize> {
+        // Gets the lines the skipped errors are on
+        skipped.iter().map(|e| match e {
+            ConfigError::Syntax(line, _) => *line,
+            _ => 0
+        }).collect()
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let contents = "\
+color red \"orphan\"
+syntax \"c\" \"\\.c$\"
+color orange \"int\"
+color latte,grey \"char\"
+color
+color green start=\"/\\*\"
+color blue \"(\" \"return\"
+color yellow \"void\"
+";
+        let mut skipped = Vec::new();
+        let syntaxes = parse_nanorc(contents, &mut skipped);
+        assert_eq!(lines(&skipped), vec![1, 3, 4, 5, 6, 7]);
+        assert_eq!(syntaxes.len(), 1);
+        assert_eq!(syntaxes[0].rules.len(), 2); // "return" (next to the bad regex) and "void"
+    }
+
+    #[test]
+    fn nameless_syntax_keeps_its_rules() {
+        let mut skipped = Vec::new();
+        let syntaxes = parse_nanorc("syntax \"c\" \"\\.c$\"\nsyntax\ncolor red \"x\"\n", &mut skipped);
+        assert_eq!(lines(&skipped), vec![2]);
+        assert_eq!(syntaxes.len(), 2);
+        assert!(syntaxes[0].rules.is_empty());
+        assert_eq!(syntaxes[1].rules.len(), 1);
+    }
+}
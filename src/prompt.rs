@@ -0,0 +1,284 @@
+extern crate ncurses;
+use ncurses::*;
+use std::char;
+use unicode_width::UnicodeWidthChar;
+use crate::colors;
+
+const MAX_HISTORY: usize = 100; // Entries kept per prompt
+
+// Earlier inputs of one kind of prompt (searches, filenames, ...), oldest first
+pub struct History {
+    entries: Vec<String>
+}
+
+impl History {
+    pub fn new() -> History {
+        History { entries: Vec::new() }
+    }
+
+    pub fn add(&mut self, entry: &str) {
+        // Adds an entry, moving it to the end if it's already there
+        if entry.is_empty() {
+            return;
+        }
+        self.entries.retain(|e| e != entry);
+        self.entries.push(entry.to_string());
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.remove(0);
+        }
+    }
+}
+
+pub enum PromptEvent {
+    Submit, // Enter
+    Cancel, // Ctrl-C
+    Edited, // The input changed
+    Unchanged, // Only the cursor moved (or the key was ignored)
+    Resized, // The terminal was resized, the caller lays the control bar out again
+    Control(char) // A control key the prompt doesn't use, for the caller to handle
+}
+
+// A single line input in the top row of the control bar, with a label in
+// front of it. The input scrolls sideways when it doesn't fit
+pub struct Prompt {
+    label: String,
+    input: Vec<char>,
+    cursor: usize, // Index into input
+    scroll: usize, // First char of input shown
+    color: i16,
+    history_pos: Option<usize>, // Entry being shown, if browsing the history
+    draft: Vec<char> // What was typed before browsing the history
+}
+
+impl Prompt {
+    pub fn new(label: &str, initial: &str) -> Prompt {
+        let input: Vec<char> = initial.chars().collect();
+        let cursor = input.len();
+        Prompt { label: label.to_string(), input, cursor, scroll: 0, color: colors::CP_HIGHLIGHT, history_pos: None, draft: Vec::new() }
+    }
+
+    pub fn set_label(&mut self, label: &str) {
+        self.label = label.to_string();
+    }
+
+    pub fn set_color(&mut self, color: i16) {
+        self.color = color;
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.set_input(text.chars().collect());
+    }
+
+    pub fn text(&self) -> String {
+        self.input.iter().collect()
+    }
+
+    pub fn draw(&mut self, window: WINDOW) {
+        // Draws the prompt and puts the cursor in it
+        let mut max_x = 0;
+        let mut max_y = 0;
+        getmaxyx(window, &mut max_y, &mut max_x);
+        let width = max_x as usize;
+
+        let label_width = str_width(&self.label);
+        let space = width.saturating_sub(label_width + 1).max(1); // Keep the last column for the cursor
+
+        // Scroll so that the cursor is visible
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        }
+        while self.scroll < self.cursor && chars_width(&self.input[self.scroll..self.cursor]) >= space {
+            self.scroll += 1;
+        }
+
+        let mut shown = String::new();
+        let mut shown_width = 0;
+        for c in self.input[self.scroll..].iter() {
+            let w = char_width(*c);
+            if shown_width + w > space {
+                break;
+            }
+            shown.push(*c);
+            shown_width += w;
+        }
+
+        wattron(window, COLOR_PAIR(self.color));
+        mvwaddstr(window, 0, 0, &self.label).unwrap();
+        waddstr(window, &shown).unwrap();
+        waddstr(window, &" ".repeat(width.saturating_sub(label_width + shown_width))).unwrap();
+        wattroff(window, COLOR_PAIR(self.color));
+        let cursor_x = label_width + chars_width(&self.input[self.scroll..self.cursor]);
+        wmove(window, 0, cursor_x.min(width.saturating_sub(1)) as i32);
+        wrefresh(window);
+    }
+
+    pub fn read(&mut self, window: WINDOW, history: &mut History) -> PromptEvent {
+        // Reads a key and applies it to the input
+        match wget_wch(window) {
+            Some(WchResult::Char(char_code)) => {
+                let c = char::from_u32(char_code).expect("Invalid char");
+                match c {
+                    '\r' => {
+                        // Enter
+                        history.add(&self.text());
+                        PromptEvent::Submit
+                    },
+                    '\u{0003}' => PromptEvent::Cancel, // Ctrl-C
+                    '\u{007F}' | '\u{0008}' => self.backspace(), // Backspace
+                    '\u{0017}' => {
+                        // Ctrl-W -> delete the word before the cursor
+                        let start = self.word_left();
+                        if start == self.cursor {
+                            beep();
+                            return PromptEvent::Unchanged;
+                        }
+                        self.input.drain(start..self.cursor);
+                        self.cursor = start;
+                        PromptEvent::Edited
+                    },
+                    '\u{0001}'..='\u{001F}' => PromptEvent::Control(c),
+                    _ => {
+                        self.input.insert(self.cursor, c);
+                        self.cursor += 1;
+                        PromptEvent::Edited
+                    }
+                }
+            },
+            Some(WchResult::KeyCode(code)) => {
+                match code {
+                    KEY_BACKSPACE => self.backspace(),
+                    KEY_DC => {
+                        // Delete
+                        if self.cursor == self.input.len() {
+                            beep();
+                            return PromptEvent::Unchanged;
+                        }
+                        self.input.remove(self.cursor);
+                        PromptEvent::Edited
+                    },
+                    KEY_LEFT => self.move_cursor(self.cursor.saturating_sub(1)),
+                    KEY_RIGHT => self.move_cursor(self.cursor + 1),
+                    KEY_HOME => self.move_cursor(0),
+                    KEY_END => self.move_cursor(self.input.len()),
+                    crate::KEY_CLEFT => self.move_cursor(self.word_left()),
+                    crate::KEY_CRIGHT => self.move_cursor(self.word_right()),
+                    KEY_UP => self.history_back(history),
+                    KEY_DOWN => self.history_forward(history),
+                    KEY_RESIZE => PromptEvent::Resized,
+                    _ => {
+                        beep();
+                        PromptEvent::Unchanged
+                    }
+                }
+            },
+            None => PromptEvent::Unchanged
+        }
+    }
+
+    // Helper functions
+
+    fn backspace(&mut self) -> PromptEvent {
+        if self.cursor == 0 {
+            beep();
+            return PromptEvent::Unchanged;
+        }
+        self.cursor -= 1;
+        self.input.remove(self.cursor);
+        PromptEvent::Edited
+    }
+
+    fn move_cursor(&mut self, pos: usize) -> PromptEvent {
+        if pos > self.input.len() {
+            beep();
+        } else {
+            self.cursor = pos;
+        }
+        PromptEvent::Unchanged
+    }
+
+    fn word_left(&self) -> usize {
+        // Gets the start of the word before the cursor
+        let mut pos = self.cursor;
+        while pos > 0 && !self.input[pos - 1].is_alphanumeric() {
+            pos -= 1;
+        }
+        while pos > 0 && self.input[pos - 1].is_alphanumeric() {
+            pos -= 1;
+        }
+        pos
+    }
+
+    fn word_right(&self) -> usize {
+        // Gets the end of the word after the cursor
+        let mut pos = self.cursor;
+        while pos < self.input.len() && !self.input[pos].is_alphanumeric() {
+            pos += 1;
+        }
+        while pos < self.input.len() && self.input[pos].is_alphanumeric() {
+            pos += 1;
+        }
+        pos
+    }
+
+    fn history_back(&mut self, history: &History) -> PromptEvent {
+        // Shows the previous history entry, keeping what was typed to come back to
+        let pos = match self.history_pos {
+            Some(0) => {
+                beep();
+                return PromptEvent::Unchanged;
+            },
+            Some(pos) => pos - 1,
+            None if history.entries.is_empty() => {
+                beep();
+                return PromptEvent::Unchanged;
+            },
+            None => {
+                self.draft = self.input.clone();
+                history.entries.len() - 1
+            }
+        };
+        self.history_pos = Some(pos);
+        self.set_input(history.entries[pos].chars().collect());
+        PromptEvent::Edited
+    }
+
+    fn history_forward(&mut self, history: &History) -> PromptEvent {
+        // Shows the next history entry, or what was typed after the last one
+        match self.history_pos {
+            None => {
+                beep();
+                return PromptEvent::Unchanged;
+            },
+            Some(pos) if pos + 1 < history.entries.len() => {
+                self.history_pos = Some(pos + 1);
+                self.set_input(history.entries[pos + 1].chars().collect());
+            },
+            Some(_) => {
+                self.history_pos = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.set_input(draft);
+            }
+        }
+        PromptEvent::Edited
+    }
+
+    fn set_input(&mut self, input: Vec<char>) {
+        self.input = input;
+        self.cursor = self.input.len();
+        self.scroll = 0;
+    }
+}
+
+pub fn char_width(c: char) -> usize {
+    // Gets the number of columns a character takes up in the terminal
+    // (2 for wide CJK characters, 0 for combining marks)
+    UnicodeWidthChar::width(c).unwrap_or(0)
+}
+
+fn chars_width(chars: &[char]) -> usize {
+    chars.iter().map(|c| char_width(*c)).sum()
+}
+
+pub fn str_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
@@ -0,0 +1,214 @@
+use regex::{Match, Regex};
+use crate::syntax_highlighting;
+
+type Range = (usize, usize); // Dijkstra range: [a, b)
+
+// A search as entered at the find prompt: either a plain string, which is
+// matched (and replaced) exactly as typed, or a regex, whose replacement
+// can refer to capture groups ($1, ${name}, ...)
+#[derive(Clone)]
+pub struct SearchQuery {
+    pub text: String,
+    pub regex: bool,
+    // Options
+    pub ignore_case: bool,
+    pub whole_word: bool,
+    pub scope: Option<Range> // Only hits within this range count (e.g. the selection)
+}
+
+impl SearchQuery {
+    pub fn new(text: String, regex: bool) -> SearchQuery {
+        SearchQuery { text, regex, ignore_case: false, whole_word: false, scope: None }
+    }
+
+    pub fn pattern(&self) -> String {
+        // Gets the query as a regex pattern, with the options applied
+        let mut pattern = if self.regex {
+            format!("(?m:{})", self.text) // ^ and $ match at every line, like nano
+        } else {
+            regex::escape(&self.text)
+        };
+        if self.whole_word {
+            pattern = format!("{}(?:{}){}", self.word_guard(self.text.chars().next()), pattern, self.word_guard(self.text.chars().last()));
+        }
+        if self.ignore_case {
+            pattern = format!("(?i:{})", pattern);
+        }
+        pattern
+    }
+
+    fn word_guard(&self, edge: Option<char>) -> &'static str {
+        // Gets what has to be at one end of a whole word hit so that it isn't
+        // next to another word char: a boundary if the query ends in a word
+        // char there, and no boundary if it doesn't (e.g. "foo(" or "-x").
+        // The ends of a regex aren't known, so those always get a boundary
+        match edge {
+            Some(c) if self.regex || c.is_alphanumeric() || c == '_' => r"\b",
+            Some(_) => r"\B",
+            None => r"\b"
+        }
+    }
+
+    pub fn compile(&self) -> Result<Regex, regex::Error> {
+        Regex::new(&self.pattern())
+    }
+
+    pub fn expand(&self, re: &Regex, text: &str, byte_start: usize, replace_with: &str) -> String {
+        // Gets the replacement for the match starting at byte_start in text.
+        // Plain string queries insert replace_with as is; regex queries expand
+        // its capture group references against the match
+        if !self.regex {
+            return replace_with.to_string();
+        }
+        match re.captures_at(text, byte_start) {
+            Some(captures) if captures.get(0).unwrap().start() == byte_start => {
+                let mut expanded = String::new();
+                captures.expand(replace_with, &mut expanded);
+                expanded
+            },
+            _ => replace_with.to_string()
+        }
+    }
+
+    pub fn replacements(&self, re: &Regex, text: &str, hits: &[Range], replace_with: &str) -> Vec<(Range, String)> {
+        // Works out the replacement for every hit before the text starts
+        // changing. Each hit is moved by the length changes of the ones
+        // before it, so the replacements can be applied in order
+        let mut pos_diff: isize = 0;
+        hits.iter().map(|&(l, r)| {
+            let replacement = self.expand(re, text, char_to_byte(text, l), replace_with);
+            let range = ((l as isize + pos_diff) as usize, (r as isize + pos_diff) as usize);
+            pos_diff += replacement.chars().count() as isize - (r - l) as isize;
+            (range, replacement)
+        }).collect()
+    }
+}
+
+pub fn find_hits(re: &Regex, text: &str, scope: Option<Range>) -> Vec<Range> {
+    // Finds every match in text, or only those within scope, as char ranges
+    let offsets = syntax_highlighting::generate_offsets(text);
+    let (start, end) = scope.unwrap_or((0, usize::MAX));
+    let mut hits = Vec::new();
+    let mut byte_index = char_to_byte(text, start);
+    while byte_index <= text.len() {
+        let m = match re.find_at(text, byte_index) {
+            Some(m) => m,
+            None => { break; }
+        };
+        let range = char_range(&offsets, m);
+        if range.1 > end {
+            break;
+        }
+        hits.push(range);
+        byte_index = syntax_highlighting::next_index(text, m.start(), m.end());
+    }
+    hits
+}
+
+pub fn char_range(offsets: &[usize], m: Match) -> Range {
+    // Converts a match's byte range to a char range, using the offset
+    // table from syntax_highlighting::generate_offsets
+    (m.start() - offsets[m.start()], m.end() - offsets[m.end()])
+}
+
+pub fn char_to_byte(text: &str, index: usize) -> usize {
+    // Converts a char offset into text to a byte offset
+    text.char_indices().nth(index).map_or(text.len(), |(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gap_buffer::GapBuffer;
+
+    fn whole_word(text: &str) -> SearchQuery {
+        SearchQuery { whole_word: true, ..SearchQuery::new(text.to_string(), false) }
+    }
+
+    const MIXED: &str = "aaaaa\n日本語テスト\n这是中文\ncafé naïve café\nend";
+
+    fn search(text: &str, query: &SearchQuery) -> Vec<Range> {
+        find_hits(&query.compile().unwrap(), text, query.scope)
+    }
+
+    fn replace_all(text: &str, query: &SearchQuery, replace_with: &str) -> String {
+        // Applies the replacements to a gap buffer the way GapEditor::replace does
+        let re = query.compile().unwrap();
+        let hits = find_hits(&re, text, query.scope);
+        let mut buffer = GapBuffer::new_from_string(text, 4);
+        for ((l, r), replacement) in query.replacements(&re, text, &hits, replace_with) {
+            buffer.cut(l, r - 1, l);
+            buffer.insert_buffer(&replacement.chars().collect());
+        }
+        buffer.export()
+    }
+
+    fn chars(text: &str, (l, r): Range) -> String {
+        text.chars().skip(l).take(r - l).collect()
+    }
+
+    #[test]
+    fn hits_are_char_offsets() {
+        let query = SearchQuery::new("テスト".to_string(), false);
+        assert_eq!(search(MIXED, &query), vec![(9, 12)]);
+        assert_eq!(chars(MIXED, (9, 12)), "テスト");
+    }
+
+    #[test]
+    fn hits_after_multibyte_text() {
+        let query = SearchQuery::new("café".to_string(), false);
+        let hits = search(MIXED, &query);
+        assert_eq!(hits, vec![(18, 22), (29, 33)]);
+        for hit in hits {
+            assert_eq!(chars(MIXED, hit), "café");
+        }
+    }
+
+    #[test]
+    fn regex_hits_on_cjk() {
+        let query = SearchQuery::new(r"[是中]+".to_string(), true);
+        let hits = search(MIXED, &query);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(chars(MIXED, hits[0]), "是中");
+    }
+
+    #[test]
+    fn scope_is_in_chars() {
+        let mut query = SearchQuery::new("café".to_string(), false);
+        query.scope = Some((20, 40));
+        assert_eq!(search(MIXED, &query), vec![(29, 33)]);
+    }
+
+    #[test]
+    fn char_to_byte_on_mixed_text() {
+        assert_eq!(char_to_byte(MIXED, 6), 6);
+        assert_eq!(char_to_byte(MIXED, 7), 9);
+        assert_eq!(char_to_byte(MIXED, 1000), MIXED.len());
+    }
+
+    #[test]
+    fn replace_with_multibyte_string() {
+        let query = SearchQuery::new("café".to_string(), false);
+        assert_eq!(replace_all(MIXED, &query, "咖啡馆"), "aaaaa\n日本語テスト\n这是中文\n咖啡馆 naïve 咖啡馆\nend");
+    }
+
+    #[test]
+    fn replace_cjk_with_ascii() {
+        let query = SearchQuery::new("中文".to_string(), false);
+        assert_eq!(replace_all(MIXED, &query, "zh"), "aaaaa\n日本語テスト\n这是zh\ncafé naïve café\nend");
+    }
+
+    #[test]
+    fn replace_with_capture_groups() {
+        let query = SearchQuery::new(r"(\w+) (\w+)".to_string(), true);
+        assert_eq!(replace_all("日本 語 naïve café", &query, "$2 $1"), "語 日本 café naïve");
+    }
+
+    #[test]
+    fn whole_word_punctuation() {
+        assert_eq!(search("foo foo( afoo( foo(x) foo(", &whole_word("foo(")), vec![(4, 8), (22, 26)]);
+        assert_eq!(search("a-x -x -xy", &whole_word("-x")), vec![(4, 6)]);
+        assert_eq!(search("a::b :: c", &whole_word("::")), vec![(5, 7)]);
+        assert_eq!(search("foo foobar (foo)", &whole_word("foo")), vec![(0, 3), (12, 15)]);
+    }
+}
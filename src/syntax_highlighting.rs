@@ -1,46 +1,41 @@
-extern crate intervaltree;
 use regex::Regex;
 use std::cmp::min;
-use std::cmp::max;
-use intervaltree::{IntervalTree, Element};
+use crate::gap_buffer::GapBuffer;
 
-// Paint Interval Tree
-pub type PaintTree = IntervalTree<usize, (u64, usize)>;
+// Highlighting works line by line. Each line caches its paints (relative to
+// the start of the line) along with the region rules that are open when the
+// line starts and ends, so an edit only re-highlights the lines it touched,
+// plus any following lines whose entry state changed as a result
 
-impl From<Paint> for Element<usize, (u64, usize)> {
-    fn from(paint: Paint) -> Self {
-        Element { range: std::ops::Range { start: paint.region_left, end: paint.region_right }, value: (paint.color, paint.priority) }
-    }
-}
+// Region rules that are still open, as (index into HighlightRules.rules, nesting depth),
+// sorted by index
+#[derive(Clone, PartialEq, Default)]
+pub struct LineState(Vec<(usize, usize)>);
 
-pub fn paint_tree_from_vecs(vec: Vec<Paint>) -> PaintTree {
-    PaintTree::from_iter(vec)
+struct HighlightedLine {
+    entry_state: LineState,
+    exit_state: LineState,
+    paints: Vec<Paint>,
+    dirty: bool // Contents changed since the paints were computed
 }
 
-pub fn paint_tree_get_color(paint_tree: &PaintTree, point: usize) -> Option<u64> {
-    // Gets the color of the lowest-priority element in paint tree
-    let hits = paint_tree.query_point(point);
-    let mut matched_color: u64 = 0;
-    let mut lowest_priority: usize = usize::MAX;
-    for hit in hits {
-        let (color, priority) = hit.value;
-        if priority < lowest_priority {
-            matched_color = color;
-            lowest_priority = priority;
-        }
-    }
-    if matched_color != 0 {
-        Some(matched_color)
-    } else {
-        None
-    }
+pub struct Highlighter {
+    rules: HighlightRules,
+    lines: Vec<HighlightedLine>,
+    first_dirty: usize // Every line before this one is up to date
 }
 
 // ----------------------
 
+#[derive(Clone)]
 pub struct SyntaxHighlight {
     regex: Regex,
-    color: u64
+    end: Option<Regex>, // If set, this is a region rule: `regex` opens the region and `end` closes it
+    color: u64,
+    // Region rule options
+    nested: bool, // Starts inside the region open another level, which needs its own end
+    escape: Option<Regex>, // Matches of this inside the region are skipped over when looking for the end
+    inner: Vec<SyntaxHighlight> // Rules that only apply inside the region
 }
 
 pub struct HighlightRules {
@@ -57,121 +52,241 @@ pub struct Paint {
 
 impl SyntaxHighlight {
     pub fn new(regex: Regex, color: u64) -> SyntaxHighlight {
-        SyntaxHighlight { regex: regex, color: color }
+        SyntaxHighlight { regex: regex, end: None, color: color, nested: false, escape: None, inner: Vec::new() }
+    }
+
+    pub fn region(start: Regex, end: Regex, color: u64) -> SyntaxHighlight {
+        // A rule spanning from a match of `start` up to and including the next
+        // match of `end`, which may be on a later line
+        SyntaxHighlight { regex: start, end: Some(end), color: color, nested: false, escape: None, inner: Vec::new() }
+    }
+
+    pub fn nested(mut self) -> SyntaxHighlight {
+        // Makes a region rule nest (e.g. rust block comments)
+        assert!(self.end.is_some());
+        self.nested = true;
+        self
+    }
+
+    pub fn with_escape(mut self, escape: Regex) -> SyntaxHighlight {
+        // Sets the escape sequence of a region rule (e.g. \\. in strings)
+        assert!(self.end.is_some());
+        self.escape = Some(escape);
+        self
+    }
+
+    pub fn with_inner(mut self, inner: Vec<SyntaxHighlight>) -> SyntaxHighlight {
+        // Sets the rules that only apply inside a region rule (e.g. TODO in comments)
+        // Inner rules are matched within a single line and can't be regions themselves
+        assert!(self.end.is_some());
+        assert!(inner.iter().all(|rule| rule.end.is_none()));
+        self.inner = inner;
+        self
+    }
+
+    fn find_region_end(&self, line: &str, mut index: usize, mut depth: usize) -> Result<usize, usize> {
+        // Scans an open region from index, returning Ok(index right after the
+        // closing match) if it closes on this line, or Err(depth) with the
+        // nesting depth left open at the end of the line
+        let end = self.end.as_ref().unwrap();
+        loop {
+            // Find whichever comes first; on ties, escapes win over ends, and ends over starts
+            let next_escape = self.escape.as_ref().and_then(|escape| escape.find_at(line, index));
+            let next_end = end.find_at(line, index);
+            let next_start = if self.nested { self.regex.find_at(line, index) } else { None };
+            let next = [next_escape, next_end, next_start].iter()
+                .enumerate()
+                .filter_map(|(kind, m)| m.map(|m| (kind, m)))
+                .min_by_key(|(kind, m)| (m.start(), *kind));
+
+            let (kind, m) = match next {
+                Some(next) => next,
+                None => { return Err(depth); }
+            };
+            match kind {
+                1 => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(m.end());
+                    }
+                },
+                2 => { depth += 1; },
+                _ => {}
+            }
+            index = next_index(line, m.start(), m.end());
+            if index > line.len() {
+                return Err(depth);
+            }
+        }
     }
 }
 
 // Basic impl
 impl HighlightRules {
     pub fn new(rules: Vec<SyntaxHighlight>) -> HighlightRules {
+        // Rules are given in order of priority: where two rules overlap, the earlier one wins
         HighlightRules { rules: rules }
     }
 
-    pub fn highlight_region(&self, buffer: String, region_left: usize, region_right: usize) -> Vec<Paint> {
-        // Returns a vector of paint regions, to be applied in order
+    pub fn highlight_line(&self, line: &str, entry_state: &LineState) -> (Vec<Paint>, LineState) {
+        // Highlights a single line (without its newline), given the region
+        // rules open at its start. Returns the paint regions (in char offsets
+        // from the start of the line) and the region rules still open at its end
 
         let mut paints = Vec::<Paint>::new();
-        let mut priority: usize = 0;
-        let offsets = generate_offsets(&buffer);
+        let mut exit_state = LineState::default();
+        let offsets = generate_offsets(line);
+        let line_len = line.len() - offsets[line.len()];
+        let to_char = |byte_index: usize| byte_index - offsets[byte_index];
+
+        for (i, highlight) in self.rules.iter().enumerate() {
+            // Rule i paints with priority 2i + 1, and its inner rules with 2i,
+            // so that they win over the region but not over earlier rules
+            let priority = 2 * i + 1;
+            let mut paint = |start: usize, end: usize, color: u64, priority: usize| {
+                if start < end {
+                    paints.push(Paint { region_left: start, region_right: end, color, priority });
+                }
+            };
+
+            if highlight.end.is_none() {
+                for m in highlight.regex.find_iter(line) {
+                    paint(to_char(m.start()), to_char(m.end()), highlight.color, priority);
+                }
+                continue;
+            }
 
-        for highlight in &self.rules {
             let mut index = 0;
-            let mut prev_index = 0;
-            //let mut offset: usize = 0;
-            let mut itcount = 0;
-            while index < region_right {
-                // Set the previous index
-                prev_index = index;
-                // Skip over the middle of unicode
-                while !buffer.is_char_boundary(index) {
-                    index += 1;
+            let mut depth = entry_state.0.iter().find(|(rule, _)| *rule == i).map_or(0, |(_, depth)| *depth);
+            while index <= line.len() {
+                let region_start = if depth > 0 {
+                    // Carried over from the previous line
+                    index
+                } else {
+                    match highlight.regex.find_at(line, index) {
+                        Some(m) => {
+                            // The end is looked for right after the start, even if it matched
+                            // nothing; an empty region is stepped over below
+                            depth = 1;
+                            index = m.end();
+                            m.start()
+                        },
+                        None => { break; }
+                    }
+                };
+
+                // The region is open: look for where it closes
+                let region_end = if index > line.len() {
+                    line.len()
+                } else {
+                    match highlight.find_region_end(line, index, depth) {
+                        Ok(end) => { depth = 0; end },
+                        Err(open_depth) => { depth = open_depth; line.len() }
+                    }
+                };
+                for inner in highlight.inner.iter() {
+                    for m in inner.regex.find_iter(&line[region_start..region_end]) {
+                        paint(to_char(region_start + m.start()), to_char(region_start + m.end()), inner.color, priority - 1);
+                    }
                 }
-                //if skip_over > 0 { panic!() };
-                match highlight.regex.find(&buffer[index..]) {
-                    Some(m) => {
-                        if m.start() + index >= region_right { // Region is entirely after the window
-                            break;
-                        }
-                        if m.end() + index <= region_left { // Region is entirely before the window
-                            index += m.end() + 1;
-                            //offset += get_offset_in_region(&buffer, prev_index, index);
-                            continue;
-                        }
-
-                        let start_index = m.start() + index;
-                        let end_index = m.end() + index;
-
-                        // Calculate the offset before
-                        //offset += get_offset_in_region(&buffer, prev_index, start_index);
-                        let start = start_index - offsets[start_index];
-
-                        // Calculate offset during
-                        //offset += get_offset_in_region(&buffer, start_index, end_index);
-                        let end = end_index - offsets[end_index];
-                        /*
-                        for i in m.start()..m.end() {
-                            if !buffer.is_char_boundary(i + index) {
-                                unicode_adjust += 1;
-                            }
-                        }
-                        */
-
-                        //if unicode_adjust > 0 { panic!(); }
-
-                        //let start = m.start() + index - offset;
-                        //let end = m.end() + index - offset - unicode_adjust;
-
-                        //offset += unicode_adjust;
-
-                        index = end_index + 1;
-
-                        // Calculate offset after
-                        //offset += get_offset_in_region(&buffer, end_index, index);
-
-                        paints.push(Paint { region_left: max(region_left, start), region_right: min(region_right, end), color: highlight.color, priority: priority });
-                    },
-                    None => { break; }
+                if depth > 0 {
+                    // Region continues onto the next line
+                    paint(to_char(region_start), line_len + 1, highlight.color, priority); // Include the newline
+                    exit_state.0.push((i, depth));
+                    break;
                 }
+                paint(to_char(region_start), to_char(region_end), highlight.color, priority);
+                index = next_index(line, region_start, region_end);
             }
-            priority += 1;
         }
-        paints
+        (paints, exit_state)
     }
 }
 
-impl Paint {
-    pub fn find_match(paints: &Vec<Paint>, idx: usize) -> Option<u64> {
-        // Finds the first paint in the vector s.t. index is within the
-        // bounds of the paint region, and returns the color (or None if
-        // there are no matches)
+impl Highlighter {
+    pub fn new(rules: HighlightRules, n_lines: usize) -> Highlighter {
+        // Creates a highlighter for a buffer with n_lines lines; nothing
+        // is highlighted until refresh is called
+        let mut lines = Vec::with_capacity(n_lines);
+        for _ in 0..n_lines {
+            lines.push(HighlightedLine::new());
+        }
+        Highlighter { rules, lines, first_dirty: 0 }
+    }
+
+    pub fn edit(&mut self, line: usize, removed_lines: usize, inserted_lines: usize) {
+        // Registers an edit that started on `line`, removed `removed_lines`
+        // newlines and inserted `inserted_lines` newlines. The paints of the
+        // lines after the edit are relative to their line, so they stay valid
+        assert!(line + removed_lines < self.lines.len());
+        self.lines.splice(line + 1..line + 1 + removed_lines, (0..inserted_lines).map(|_| HighlightedLine::new()));
+        self.lines[line].dirty = true;
+        self.first_dirty = min(self.first_dirty, line);
+    }
 
-        for paint in paints {
-            if paint.region_left <= idx && idx < paint.region_right {
-                return Some(paint.color);
+    pub fn refresh(&mut self, buffer: &GapBuffer, upto_line: usize) {
+        // Brings the paints of every line up to and including upto_line up to date
+        let last_line = min(upto_line, self.lines.len() - 1);
+        if self.first_dirty > last_line {
+            return;
+        }
+
+        let mut pos = buffer.line_start(self.first_dirty);
+        for i in self.first_dirty..=last_line {
+            let right_edge = buffer.get_right_edge(pos);
+            let entry_state = if i == 0 { LineState::default() } else { self.lines[i - 1].exit_state.clone() };
+            let line = &mut self.lines[i];
+            if line.dirty || line.entry_state != entry_state {
+                let text: String = (pos..right_edge).filter_map(|j| buffer.get(j)).collect();
+                let (paints, exit_state) = self.rules.highlight_line(&text, &entry_state);
+                line.paints = paints;
+                line.entry_state = entry_state;
+                line.exit_state = exit_state;
+                line.dirty = false;
             }
+            pos = right_edge + 1;
         }
-        None
+        self.first_dirty = last_line + 1;
+    }
+
+    pub fn get_color(&self, line: usize, col: usize) -> Option<u64> {
+        // Gets the color of the highest priority paint covering column col of
+        // the line (call refresh first)
+        let paints = &self.lines.get(line)?.paints;
+        paints.iter()
+            .filter(|paint| paint.region_left <= col && col < paint.region_right)
+            .min_by_key(|paint| paint.priority)
+            .map(|paint| paint.color)
     }
 }
 
-fn get_offset_in_region(buffer: &String, start_index: usize, end_index: usize) -> usize {
-    // Gets the byte offset of region in buffer
-    let mut offset = 0;
-    for i in start_index..end_index {
-        if !buffer.is_char_boundary(i) {
-            offset += 1;
-        }
+impl HighlightedLine {
+    fn new() -> HighlightedLine {
+        HighlightedLine { entry_state: LineState::default(), exit_state: LineState::default(), paints: Vec::new(), dirty: true }
+    }
+}
+
+pub fn next_index(line: &str, start: usize, end: usize) -> usize {
+    // Where to continue searching after a match at start..end: right after
+    // it, or one character on for empty matches so we always make progress
+    if end > start {
+        return end;
+    }
+    let mut index = end + 1;
+    while index < line.len() && !line.is_char_boundary(index) {
+        index += 1;
     }
-    offset
+    index
 }
 
-fn generate_offsets(buffer: &String) -> Vec<usize> {
-    // Generates a vector of offset values
+pub fn generate_offsets(buffer: &str) -> Vec<usize> {
+    // Generates a vector of offset values (byte index - char index), with
+    // one extra entry so that the end of the buffer can be looked up too
 
     let mut offsets = Vec::<usize>::new();
     let mut offset_count: usize = 0;
- 
-   for i in 0..buffer.len() {
+
+   for i in 0..=buffer.len() {
         if !buffer.is_char_boundary(i) {
             offset_count += 1;
         }
@@ -179,3 +294,42 @@ fn generate_offsets(buffer: &String) -> Vec<usize> {
     }
     offsets
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn painted(rules: &HighlightRules, line: &str) -> Vec<(usize, usize, usize)> {
+        // Gets the paints as (start, end, priority)
+        let (paints, _) = rules.highlight_line(line, &LineState::default());
+        paints.iter().map(|paint| (paint.region_left, paint.region_right, paint.priority)).collect()
+    }
+
+    fn region(start: &str, end: &str, color: u64) -> SyntaxHighlight {
+        SyntaxHighlight::region(Regex::new(start).unwrap(), Regex::new(end).unwrap(), color)
+    }
+
+    #[test]
+    fn region_after_zero_width_start() {
+        // The end is found right where the empty start matched, without skipping a character
+        let rules = HighlightRules::new(vec![region("^", "x", 1)]);
+        assert_eq!(painted(&rules, "xab x"), vec![(0, 1, 1)]);
+    }
+
+    #[test]
+    fn empty_region_makes_progress() {
+        let rules = HighlightRules::new(vec![region("", "", 1)]);
+        assert_eq!(painted(&rules, "abc"), vec![]);
+    }
+
+    #[test]
+    fn raw_strings_end_with_their_own_hashes() {
+        let rules = crate::syntax_highlighting_demo::build_highlighting_rules();
+        let line = r###"let s = r##"a "# b"##;"###;
+        let raw_strings: Vec<(usize, usize)> = painted(&rules, line).into_iter()
+            .filter(|(_, _, priority)| [7, 9, 11].contains(priority)) // Rules 3 to 5
+            .map(|(l, r, _)| (l, r))
+            .collect();
+        assert_eq!(raw_strings, vec![(8, 21)]);
+    }
+}
@@ -27,39 +27,43 @@ pub fn build_highlighting_rules() -> HighlightRules {
 pub fn build_highlighting_rules() -> HighlightRules {
     // Builds highlighting rules
 
+    // A raw string only ends at a quote followed by as many #s as it started with
+    // The regex crate has no backreferences, so each count gets its own rule
+    let raw_string = |hashes: usize| {
+        let hashes = "#".repeat(hashes);
+        SyntaxHighlight::region(
+            Regex::new(&format!(r#"r{}""#, hashes)).unwrap(),
+            Regex::new(&format!(r#""{}"#, hashes)).unwrap(),
+            COLOR_PAIR(colors::CP_YELLOW)
+        )
+    };
+
     let syntax_highlightings: Vec<SyntaxHighlight> = vec![
-        // Comment markers
-        SyntaxHighlight::new(
-            Regex::new(r"(XXX|TODO|FIXME|\?\?\?)").unwrap(),
-            COLOR_PAIR(colors::CP_CYAN) | A_BOLD
-        ),
         // Attributes
         SyntaxHighlight::new(
             Regex::new(r"#!\[.*\]").unwrap(),
             COLOR_PAIR(colors::CP_MAGENTA)
         ),
-        // Comments
-        SyntaxHighlight::new(
-            Regex::new(r"/\*(.|\n)*?\*/").unwrap(),
+        // Comments (block comments nest in rust), with comment markers inside them
+        SyntaxHighlight::region(
+            Regex::new(r"/\*").unwrap(),
+            Regex::new(r"\*/").unwrap(),
             COLOR_PAIR(colors::CP_GREEN)
-        ),
-        SyntaxHighlight::new(
-            Regex::new(r"//.*").unwrap(),
+        ).nested().with_inner(comment_markers()),
+        SyntaxHighlight::region(
+            Regex::new(r"//").unwrap(),
+            Regex::new(r"$").unwrap(),
             COLOR_PAIR(colors::CP_GREEN)
-        ),
+        ).with_inner(comment_markers()),
         // Strings
-        SyntaxHighlight::new(
-            Regex::new(r##"r#+\"(.|\n)*?\"#+"##).unwrap(),
+        raw_string(1),
+        raw_string(2),
+        raw_string(3),
+        SyntaxHighlight::region(
+            Regex::new(r#"""#).unwrap(),
+            Regex::new(r#"""#).unwrap(),
             COLOR_PAIR(colors::CP_YELLOW)
-        ),
-        SyntaxHighlight::new(
-            Regex::new(r##"\\".*\\$(.|\n)*?.*\\""##).unwrap(),
-            COLOR_PAIR(colors::CP_YELLOW)
-        ),
-        SyntaxHighlight::new(
-            Regex::new(r#"\"(.|\n)*?\""#).unwrap(),
-            COLOR_PAIR(colors::CP_YELLOW)
-        ),
+        ).with_escape(Regex::new(r"\\.").unwrap()),
         // Classes
         SyntaxHighlight::new(
             Regex::new(r"[A-Z][A-Za-z0-9]+").unwrap(),
@@ -90,3 +94,13 @@ pub fn build_highlighting_rules() -> HighlightRules {
 
     HighlightRules::new(syntax_highlightings)
 }
+
+fn comment_markers() -> Vec<SyntaxHighlight> {
+    // Highlighted only inside comments
+    vec![
+        SyntaxHighlight::new(
+            Regex::new(r"(XXX|TODO|FIXME|\?\?\?)").unwrap(),
+            COLOR_PAIR(colors::CP_CYAN) | A_BOLD
+        )
+    ]
+}
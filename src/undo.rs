@@ -4,6 +4,7 @@ pub enum Action {
     TypeChar(usize, char, usize), // TypeChar(position, character, end_position)
     Newline(usize, usize), // Newline(position, end_position
     Delete(usize, char, usize), // Delete(position, deleted_character, end_position)
+    DeleteForward(usize, char), // DeleteForward(position, deleted_character); the cursor stays at position
     Replace(usize, String, String), // Replace(range_left, replaced_string, replace_string)
     Cut(usize, usize, String, usize), // Cut(start_position, range_left, cut_string, end_position)
     Insert(usize, usize, String, usize) // Insert(start_position, range_l, pasted_string, end_position)
@@ -26,6 +27,7 @@ impl Action {
                 '\n' => Self::Newline(*end, *pos),
                 _ => Self::TypeChar(*end, *ch, *pos)
             },
+            Self::DeleteForward(pos, ch) => Self::Insert(*pos, *pos, ch.to_string(), *pos),
             Self::Replace(range_l, replaced, replacing) => Self::Replace(*range_l, replacing.clone(), replaced.clone()),
             Self::Cut(start, range_l, cut_string, end) => Self::Insert(*end, *range_l, cut_string.clone(), *start),
             Self::Insert(start, range_l, paste_string, end) => Self::Cut(*end, *range_l, paste_string.clone(), *start)